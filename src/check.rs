@@ -0,0 +1,124 @@
+// [[file:../magman.note::9b4d2f61][9b4d2f61]]
+use super::*;
+use std::path::Path;
+// 9b4d2f61 ends here
+
+// [[file:../magman.note::3e7a5c08][3e7a5c08]]
+/// Severity of a problem found in pre-flight check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Jobs will fail or produce wrong results.
+    Error,
+    /// Suspicious settings that magman works around.
+    Warning,
+}
+
+/// A problem found in pre-flight check.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// The checked item, such as `INCAR` or `cmdline`.
+    pub item: String,
+    pub message: String,
+}
+
+/// All problems found in pre-flight check.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// Record an error on `item`.
+    pub fn error(&mut self, item: &str, message: impl std::fmt::Display) {
+        self.push(Severity::Error, item, message);
+    }
+
+    /// Record a warning on `item`.
+    pub fn warning(&mut self, item: &str, message: impl std::fmt::Display) {
+        self.push(Severity::Warning, item, message);
+    }
+
+    fn push(&mut self, severity: Severity, item: &str, message: impl std::fmt::Display) {
+        self.problems.push(Problem {
+            severity,
+            item: item.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Return the number of errors.
+    pub fn nerrors(&self) -> usize {
+        self.problems.iter().filter(|p| p.severity == Severity::Error).count()
+    }
+
+    /// Return true if no error found.
+    pub fn is_ok(&self) -> bool {
+        self.nerrors() == 0
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for p in self.problems.iter() {
+            let severity = match p.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            writeln!(f, "{:<8} {:<10} {}", severity, p.item, p.message)?;
+        }
+        let nerrors = self.nerrors();
+        write!(f, "{} errors, {} warnings.", nerrors, self.problems.len() - nerrors)
+    }
+}
+
+/// Check config file in `path`, VASP template directory and related settings
+/// before running any job. Errors in loading config are reported instead of
+/// panicking.
+pub fn check_config(path: &Path) -> Report {
+    match crate::config::Config::from_file(path) {
+        Ok(config) => check_settings(&config),
+        Err(e) => {
+            let mut report = Report::default();
+            report.error("config", format!("{:#}", e));
+            report
+        }
+    }
+}
+
+fn check_settings(config: &crate::config::Config) -> Report {
+    let mut report = Report::default();
+    config.vasp.check_template(config.search.genome_length, &mut report);
+    let n = config.search.genome_length.or_else(|| config.vasp.find_magnetic_sites(None).ok().map(|x| x.sites.len()));
+    if let Some(n) = n {
+        if let Err(e) = config.search.spin_up_counts(n) {
+            report.error("search", e);
+        }
+    }
+    if config.search.net_magnetization.is_some() && !config.vasp.spin_states().is_binary() {
+        report.error("search", "net magnetization can be constrained only for up/down spin states");
+    }
+
+    report
+}
+// 3e7a5c08 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_check_malformed_config() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("magman.conf");
+    gut::fs::write_to_file(&path, "[vasp\ncmdline = 'run-vasp.sh'\n")?;
+    let report = check_config(&path);
+    assert_eq!(report.nerrors(), 1);
+    assert_eq!(report.problems[0].item, "config");
+    assert!(report.problems[0].message.contains("parse"));
+
+    let report = check_config(&dir.path().join("missing.conf"));
+    assert_eq!(report.nerrors(), 1);
+    assert!(report.problems[0].message.contains("read"));
+
+    Ok(())
+}
+// test:1 ends here
@@ -6,6 +6,8 @@ use gut::fs::*;
 // 3a532d42 ends here
 
 // [[file:../magman.note::d4bc87e0][d4bc87e0]]
+use clap::Subcommand;
+
 /// Predict ground-state magnetic ordering of magnetic system.
 #[derive(Debug, StructOpt)]
 #[clap(author, version, about)]
@@ -13,56 +15,228 @@ struct Cli {
     #[structopt(flatten)]
     verbose: Verbosity,
 
-    /// Prints default configuration.
-    #[structopt(long = "print", short = 'p')]
-    print: bool,
+    /// Path to config file.
+    #[structopt(long = "config", global = true, default_value = "magman.conf")]
+    config: PathBuf,
+
+    /// Path to database file. Defaults to `GOSH_DATABASE_URL` env var, or
+    /// `magman.db` if it is not set.
+    #[structopt(long = "db", global = true)]
+    db: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Create default configuration file.
+    Init {
+        /// Prints default configuration instead of writing into file.
+        #[clap(long = "print", short = 'p')]
+        print: bool,
+
+        /// Overwrite existing config file.
+        #[clap(long = "force")]
+        force: bool,
+    },
+
+    /// Run searching for the ground-state spin-ordering.
+    Run {
+        /// Specifies the number of jobs to run simultaneously
+        #[clap(long = "jobs", short = 'j', default_value = "1")]
+        njobs: usize,
+
+        /// Run exhaustive enumeration of all spin-orderings instead of
+        /// genetic search.
+        #[clap(long = "enumerate", short = 'e', conflicts_with = "surrogate")]
+        enumerate: bool,
+
+        /// Run genetic search assisted by a surrogate exchange model.
+        #[clap(long = "surrogate", short = 's')]
+        surrogate: bool,
+    },
 
     /// List calculated items in database.
-    #[structopt(long = "list", short = 'l')]
-    list: bool,
+    List,
 
     /// Collect data from completed job files.
-    #[structopt(long = "collect", short = 'c', parse(from_os_str))]
-    collect: Option<PathBuf>,
+    Collect {
+        /// The directory containing job directories. Defaults to the
+        /// working directory in config.
+        #[clap(parse(from_os_str))]
+        dir: Option<PathBuf>,
+    },
+
+    /// Show details of a calculated item in database.
+    Show {
+        /// The key of spin-ordering, such as `100110011001`.
+        key: String,
+    },
+
+    /// Export calculated items in database as a table.
+    Export {
+        /// The output format.
+        #[clap(long = "format", short = 'f', default_value = "csv", possible_values = &["csv", "json"])]
+        format: String,
+
+        /// Write into file instead of stdout.
+        #[clap(long = "output", short = 'o', parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Import results from csv tables or finished job directories into
+    /// database.
+    Import {
+        /// The csv files in `directory,energy,seqs,net_mag` format, or
+        /// directories containing job directories.
+        #[clap(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Overwrite existing items in database with different energies.
+        #[clap(long = "force")]
+        force: bool,
+
+        /// Energies differing less than this value are treated as the same.
+        #[clap(long = "tolerance", default_value = "1e-4")]
+        tolerance: f64,
+    },
+
+    /// Fit Heisenberg exchange model using calculated items in database.
+    Fit {
+        /// The number of neighbor shells.
+        #[clap(long = "nshells", short = 'n', default_value = "3")]
+        nshells: usize,
+    },
+
+    /// Benchmark search strategies against a replay table using many
+    /// random seeds.
+    Bench {
+        /// The search strategies to be compared.
+        #[clap(long = "strategy", short = 's', default_values = &["ga", "random"], possible_values = &["ga", "random"])]
+        strategies: Vec<String>,
 
-    /// Run genetic search.
-    #[structopt(long = "run", short = 'r')]
-    run: bool,
+        /// The csv or json table of precomputed energies. Defaults to the
+        /// replay table in config.
+        #[clap(long = "table", parse(from_os_str))]
+        table: Option<PathBuf>,
 
-    /// Specifies the number of jobs to run simultaneously
-    #[structopt(long = "jobs", short = 'j', default_value = "1")]
-    njobs: usize,
+        /// The number of random seeds.
+        #[clap(long = "nseeds", short = 'n', default_value = "100")]
+        nseeds: usize,
+
+        /// The first random seed.
+        #[clap(long = "seed", default_value = "0")]
+        seed: u64,
+    },
+
+    /// Check VASP template directory and config before running jobs.
+    Check {
+        /// Print the report in json format.
+        #[clap(long = "json")]
+        json: bool,
+    },
+
+    /// Show status of database and job directories.
+    Status,
+
+    /// Remove unfinished job directories.
+    Clean {
+        /// Only print directories to be removed.
+        #[clap(long = "dry-run")]
+        dry_run: bool,
+
+        /// Also clear failed spin-orderings in database and remove their job
+        /// directories. Failed spin-orderings are never evaluated again
+        /// until cleared, such as after fixing the VASP template.
+        #[clap(long = "failed")]
+        failed: bool,
+    },
 }
 
 pub fn enter_main() -> Result<()> {
     let args = Cli::from_args();
     args.verbose.setup_logger();
 
-    if args.print {
-        println!("{:#^72}", " default configuration ");
-        config::Config::default().print_toml();
-        return Ok(());
+    config::set_config_file(&args.config);
+    if let Some(db) = &args.db {
+        set_database(db);
     }
 
-    // run in serial by default
-    let njobs = args.njobs;
-    std::env::set_var("RAYON_NUM_THREADS", njobs.to_string());
-    if njobs > 1 {
-        println!("Run {njobs} in parallel");
-    }
+    match args.cmd {
+        Cmd::Init { print, force } => {
+            if print {
+                println!("{:#^72}", " default configuration ");
+                config::Config::default().print_toml();
+            } else if args.config.exists() && !force {
+                bail!("config file {} already exists. Use --force to overwrite.", args.config.display());
+            } else {
+                config::Config::default().write_toml(&args.config)?;
+                println!("default configuration written into {}", args.config.display());
+            }
+        }
+        Cmd::Run {
+            njobs,
+            enumerate,
+            surrogate,
+        } => {
+            // run in serial by default
+            std::env::set_var("RAYON_NUM_THREADS", njobs.to_string());
+            if njobs > 1 {
+                println!("Run {njobs} in parallel");
+            }
 
-    if args.run {
-        if let Err(err) = search::genetic_search() {
-            bail!("genetic search failure: {err:?}");
+            if enumerate {
+                if let Err(err) = search::exhaustive_search() {
+                    bail!("exhaustive search failure: {err:?}");
+                }
+            } else if surrogate {
+                if let Err(err) = search::surrogate_search() {
+                    bail!("surrogate search failure: {err:?}");
+                }
+            } else if let Err(err) = search::genetic_search() {
+                bail!("genetic search failure: {err:?}");
+            }
+        }
+        Cmd::List => {
+            // setup a pager like `less` cmd
+            pager::Pager::with_pager("less").setup();
+            list_db()?;
+        }
+        Cmd::Collect { dir } => {
+            collect_results_from_dir(dir.as_deref())?;
+        }
+        Cmd::Show { key } => {
+            show_item(&key)?;
+        }
+        Cmd::Export { format, output } => {
+            export_db(&format, output.as_deref())?;
+        }
+        Cmd::Import { paths, force, tolerance } => {
+            for path in paths {
+                import_into_db(&path, force, tolerance)?;
+            }
+        }
+        Cmd::Fit { nshells } => {
+            fit_exchange_model(nshells)?;
+        }
+        Cmd::Bench {
+            strategies,
+            table,
+            nseeds,
+            seed,
+        } => {
+            search::benchmark(&strategies, table.as_deref(), nseeds, seed)?;
+        }
+        Cmd::Check { json } => {
+            check_template(json)?;
+        }
+        Cmd::Status => {
+            show_status()?;
+        }
+        Cmd::Clean { dry_run, failed } => {
+            clean_jobs(dry_run, failed)?;
         }
-    } else if args.list {
-        // setup a pager like `less` cmd
-        pager::Pager::with_pager("less").setup();
-        list_db()?;
-    } else if let Some(dir) = args.collect {
-        collect_results_from_dir(&dir)?;
-    } else {
-        Cli::clap().print_help()?;
     }
 
     Ok(())
@@ -2,16 +2,52 @@
 use serde::*;
 use toml;
 
+/// Environment variable for the path to config file.
+const CONFIG_FILE_VAR: &str = "MAGMAN_CONFIG_FILE";
+
 lazy_static! {
     /// Global settings.
     pub static ref MAGMAN_CONFIG: Config = {
-        let config_file = format!("{}.conf", env!("CARGO_PKG_NAME"));
+        let config_file = config_file();
         println!("configfile {}", config_file);
 
-        let toml_str = gut::fs::read_file(config_file).expect("Failed to read config file!");
-        toml::from_str(&toml_str).expect("Failed to parse toml config!")
+        Config::from_file(config_file).expect("Failed to load config file!")
+    };
+
+    /// Magnetic sites in template POSCAR, or the error message if not
+    /// found.
+    static ref MAGNETIC_SITES: Result<crate::poscar::MagneticSites, String> = {
+        let config = &MAGMAN_CONFIG;
+        config.vasp.find_magnetic_sites(config.search.genome_length).map_err(|e| format!("{:#}", e))
     };
 }
+
+/// Return magnetic sites in template POSCAR.
+pub fn magnetic_sites() -> gut::prelude::Result<&'static crate::poscar::MagneticSites> {
+    MAGNETIC_SITES
+        .as_ref()
+        .map_err(|e| gut::prelude::anyhow!("Failed to find magnetic sites: {}", e))
+}
+
+/// Return the number of magnetic sites, which is `genome_length` in config
+/// if set, or derived from template POSCAR.
+pub fn genome_length() -> gut::prelude::Result<usize> {
+    match MAGMAN_CONFIG.search.genome_length {
+        Some(n) => Ok(n),
+        None => Ok(magnetic_sites()?.sites.len()),
+    }
+}
+
+/// Set the path to config file. This should be called before accessing
+/// `MAGMAN_CONFIG`.
+pub fn set_config_file(path: &std::path::Path) {
+    std::env::set_var(CONFIG_FILE_VAR, path);
+}
+
+/// Return the path to config file, defaults to `magman.conf`.
+pub fn config_file() -> String {
+    std::env::var(CONFIG_FILE_VAR).unwrap_or_else(|_| format!("{}.conf", env!("CARGO_PKG_NAME")))
+}
 // imports:1 ends here
 
 // [[file:../magman.note::4e733dd2][4e733dd2]]
@@ -22,6 +58,14 @@ pub struct Config {
 
     /// Genetic search parameters.
     pub search: Search,
+
+    /// Surrogate-assisted search parameters.
+    #[serde(default)]
+    pub surrogate: Surrogate,
+
+    /// Replay evaluations from a table of precomputed energies instead of
+    /// running VASP.
+    pub replay: Option<crate::replay::Replay>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
@@ -30,9 +74,118 @@ pub struct Search {
     pub target_energy: Option<f64>,
     pub population_size: usize,
     pub boltzmann_temperature: f64,
+    /// The probability of mutating each newly bred genome.
     pub mutation_rate: f64,
-    pub genome_length: usize,
+    /// The mutation operator: "bit-flip" or "swap".
+    #[serde(default)]
+    pub mutation: crate::operators::Mutation,
+    /// The crossover operator: "triadic", "uniform", "one-point" or
+    /// "two-point".
+    #[serde(default)]
+    pub crossover: crate::operators::Crossover,
+    /// The selection operator: "sus", "tournament" or "roulette".
+    #[serde(default)]
+    pub selector: crate::operators::Selector,
+    /// The number of members competing in each tournament selection.
+    #[serde(default = "default_tournament_size")]
+    pub tournament_size: usize,
+    /// The number of magnetic sites. Derived from `magnetic_sites` in
+    /// template POSCAR if not set. Without `magnetic_sites`, the first
+    /// `genome_length` atoms in POSCAR are treated as magnetic sites.
+    #[serde(default)]
+    pub genome_length: Option<usize>,
     pub termination_nlast: usize,
+    /// The max number of spin-orderings to be evaluated in exhaustive
+    /// enumeration, including failed ones. It is required for more combinations
+    /// than those of 16 magnetic sites.
+    pub max_evaluations: Option<usize>,
+    /// Seed the initial population from the population saved in previous
+    /// run, or from the lowest-energy items in database.
+    #[serde(default)]
+    pub resume: bool,
+    /// The fraction of random genomes in the initial population when
+    /// resuming.
+    #[serde(default = "default_immigrant_fraction")]
+    pub immigrant_fraction: f64,
+    /// The file for saving population of each generation.
+    #[serde(default = "default_population_file")]
+    pub population_file: std::path::PathBuf,
+    /// The allowed net magnetization |n_up - n_down| in number of spins.
+    /// Only spin-orderings in this magnetic sector will be searched if set.
+    pub net_magnetization: Option<Vec<usize>>,
+    /// The energy assigned to spin-orderings failed in evaluation, which
+    /// makes them unlikely to survive in genetic search. If not set, the
+    /// highest energy evaluated so far plus `failure_margin` is used.
+    pub failure_energy: Option<f64>,
+    /// The margin above the highest evaluated energy for penalizing failed
+    /// spin-orderings when `failure_energy` is not set.
+    #[serde(default = "default_failure_margin")]
+    pub failure_margin: f64,
+    /// Abort the search on any failed VASP calculation.
+    #[serde(default)]
+    pub abort_on_failure: bool,
+    /// The seed for random number generator. A random seed will be used if
+    /// it is not set, which is printed in run log for reproducing the search.
+    pub random_seed: Option<u64>,
+}
+
+fn default_failure_margin() -> f64 {
+    1.0
+}
+
+fn default_tournament_size() -> usize {
+    2
+}
+
+fn default_immigrant_fraction() -> f64 {
+    0.2
+}
+
+fn default_population_file() -> std::path::PathBuf {
+    "population.json".into()
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Surrogate {
+    /// The number of neighbor shells in exchange model.
+    pub nshells: usize,
+    /// The number of generations evolved against exchange model in each
+    /// cycle.
+    pub max_generations: usize,
+    /// The number of top candidates to be verified by VASP in each cycle.
+    pub top_k: usize,
+    /// The max number of fit/predict/verify cycles.
+    pub max_cycles: usize,
+}
+
+impl Default for Surrogate {
+    fn default() -> Self {
+        Self {
+            nshells: 3,
+            max_generations: 50,
+            top_k: 4,
+            max_cycles: 20,
+        }
+    }
+}
+
+impl Search {
+    /// Return the allowed numbers of spin-up sites in genome, constrained by
+    /// `net_magnetization` for `n` magnetic sites. The first site, which is
+    /// fixed as spin-up, is excluded from the counting. Return None if it is
+    /// not constrained.
+    pub fn spin_up_counts(&self, n: usize) -> gut::prelude::Result<Option<Vec<usize>>> {
+        match &self.net_magnetization {
+            Some(allowed) => {
+                let nups: Vec<_> = (1..=n).filter(|&u| allowed.contains(&(2 * u).abs_diff(n))).map(|u| u - 1).collect();
+                if nups.is_empty() {
+                    gut::prelude::bail!("no spin-orderings of {} sites with net magnetization in {:?}", n, allowed);
+                }
+                Ok(Some(nups))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 impl Default for Config {
@@ -44,18 +197,59 @@ impl Default for Config {
                 max_generations: 10,
                 termination_nlast: 50,
                 target_energy: None,
-                genome_length: 12,
+                genome_length: None,
                 mutation_rate: 0.1,
+                mutation: Default::default(),
+                crossover: Default::default(),
+                selector: Default::default(),
+                tournament_size: default_tournament_size(),
                 boltzmann_temperature: 5000.0,
+                max_evaluations: None,
+                resume: false,
+                immigrant_fraction: default_immigrant_fraction(),
+                population_file: default_population_file(),
+                net_magnetization: None,
+                random_seed: None,
+                failure_energy: None,
+                failure_margin: default_failure_margin(),
+                abort_on_failure: false,
             },
+            surrogate: Surrogate::default(),
+            replay: None,
         }
     }
 }
 
 impl Config {
+    /// Return the evaluator of spin-orderings: the replay table if set,
+    /// otherwise VASP.
+    pub fn evaluator(&self) -> &dyn crate::magmom::EvaluateMagneticState {
+        match &self.replay {
+            Some(replay) => replay.table(),
+            None => &self.vasp,
+        }
+    }
+
+    /// Read config in toml format from file `path`.
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> gut::prelude::Result<Self> {
+        use gut::prelude::Context;
+
+        let path = path.as_ref();
+        let toml_str = gut::fs::read_file(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config = toml::from_str(&toml_str).with_context(|| format!("Failed to parse toml config {}", path.display()))?;
+        Ok(config)
+    }
+
     pub fn print_toml(&self) {
         let x = toml::to_string(self).unwrap();
         println!("{:}", x);
     }
+
+    /// Write config in toml format into file `path`.
+    pub fn write_toml(&self, path: &std::path::Path) -> gut::prelude::Result<()> {
+        let x = toml::to_string(self)?;
+        gut::fs::write_to_file(path, &x)?;
+        Ok(())
+    }
 }
 // 4e733dd2 ends here
@@ -0,0 +1,301 @@
+// [[file:../magman.note::7c41e0d2][7c41e0d2]]
+use super::*;
+use crate::magmom::MagneticState;
+use crate::poscar::Poscar;
+use crate::spin::SpinStates;
+// 7c41e0d2 ends here
+
+// [[file:../magman.note::e93b6a5f][e93b6a5f]]
+/// Tolerance in Angstrom for grouping pair distances into neighbor shells.
+const SHELL_TOLERANCE: f64 = 1e-2;
+
+/// Neighbor shells of magnetic sites, grouped by pair distance.
+#[derive(Debug, Clone)]
+pub struct NeighborShells {
+    /// Pair distance of each shell.
+    distances: Vec<f64>,
+    /// Site pairs and their weights in each shell, counting periodic images.
+    pairs: Vec<Vec<(usize, usize, f64)>>,
+}
+
+impl NeighborShells {
+    /// Find the `nshells` nearest neighbor shells among magnetic sites in
+    /// `sites` (atom indices in POSCAR order) of crystal `poscar`.
+    pub fn from_poscar(poscar: &Poscar, sites: &[usize], nshells: usize) -> Result<Self> {
+        if nshells == 0 {
+            bail!("the number of neighbor shells should be at least 1");
+        }
+
+        // collect all pair distances up to two cells away
+        let mut bonds = vec![];
+        for (a, &i) in sites.iter().enumerate() {
+            for (b, &j) in sites.iter().enumerate() {
+                for t in image_translations(2) {
+                    let d = [0, 1, 2].map(|k| poscar.positions[j][k] + t[k] - poscar.positions[i][k]);
+                    let r = norm(poscar.to_cartesian(d));
+                    if r > SHELL_TOLERANCE {
+                        bonds.push((r, a, b));
+                    }
+                }
+            }
+        }
+        bonds.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+        let mut distances: Vec<f64> = vec![];
+        let mut pairs: Vec<Vec<(usize, usize, f64)>> = vec![];
+        for (r, a, b) in bonds {
+            match distances.last() {
+                Some(&d) if r - d < SHELL_TOLERANCE => {}
+                _ => {
+                    if distances.len() == nshells {
+                        break;
+                    }
+                    distances.push(r);
+                    pairs.push(vec![]);
+                }
+            }
+            // each bond is counted twice in ordered pairs
+            let shell = pairs.last_mut().unwrap();
+            match shell.iter_mut().find(|p| p.0 == a && p.1 == b) {
+                Some(p) => p.2 += 0.5,
+                None => shell.push((a, b, 0.5)),
+            }
+        }
+        if distances.len() < nshells {
+            bail!("only {} neighbor shells found within the cutoff", distances.len());
+        }
+
+        Ok(Self { distances, pairs })
+    }
+
+    /// Return the number of neighbor shells.
+    pub fn nshells(&self) -> usize {
+        self.distances.len()
+    }
+
+    /// Return the pair distance of each neighbor shell.
+    pub fn distances(&self) -> &[f64] {
+        &self.distances
+    }
+
+    /// Return pair-interaction descriptors Σ s_i·s_j in each shell for
+    /// relative magnetic moment vectors `spins` of magnetic sites.
+    pub fn descriptors(&self, spins: &[[f64; 3]]) -> Vec<f64> {
+        let dot = |a: &[f64; 3], b: &[f64; 3]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+        self.pairs
+            .iter()
+            .map(|shell| shell.iter().map(|&(a, b, w)| w * dot(&spins[a], &spins[b])).sum())
+            .collect()
+    }
+}
+
+/// Heisenberg/Ising exchange model: E = E0 + Σ J_ij s_i s_j with couplings
+/// J grouped by neighbor shells.
+#[derive(Debug, Clone)]
+pub struct ExchangeModel {
+    shells: NeighborShells,
+    spins: SpinStates,
+    /// The reference energy E0.
+    pub e0: f64,
+    /// The exchange coupling constants of each shell.
+    pub couplings: Vec<f64>,
+}
+
+impl ExchangeModel {
+    /// Fit exchange couplings from evaluated magnetic states `states` in
+    /// `spins` by least-squares. States relaxed into different
+    /// spin-orderings are skipped, as their energies do not belong to the
+    /// requested spin-orderings.
+    pub fn fit(shells: NeighborShells, spins: &SpinStates, states: &[MagneticState]) -> Result<Self> {
+        let states: Vec<_> = states.iter().filter(|ms| !ms.spin_flipped).collect();
+        let nparams = shells.nshells() + 1;
+        if states.len() < nparams {
+            bail!("too few magnetic states ({}) for fitting {} parameters", states.len(), nparams);
+        }
+        let x: Vec<_> = states.iter().map(|ms| design_row(&shells, spins, &ms.spin_ordering)).collect();
+        let y: Vec<_> = states.iter().map(|ms| ms.energy).collect();
+        let params = least_squares(&x, &y)?;
+
+        Ok(Self {
+            shells,
+            spins: spins.clone(),
+            e0: params[0],
+            couplings: params[1..].to_vec(),
+        })
+    }
+
+    /// Predict energy of spin-ordering `so`.
+    pub fn predict(&self, so: &[u8]) -> f64 {
+        let x = self.shells.descriptors(&spin_values(&self.spins, so));
+        self.e0 + x.iter().zip(self.couplings.iter()).map(|(x, j)| x * j).sum::<f64>()
+    }
+
+    /// Return root mean square error of leave-one-out cross validation over
+    /// `states`, skipping spin-flipped ones.
+    pub fn loocv_error(&self, states: &[MagneticState]) -> Result<f64> {
+        let states: Vec<_> = states.iter().filter(|ms| !ms.spin_flipped).collect();
+        let x: Vec<_> = states
+            .iter()
+            .map(|ms| design_row(&self.shells, &self.spins, &ms.spin_ordering))
+            .collect();
+        let y: Vec<_> = states.iter().map(|ms| ms.energy).collect();
+
+        let mut sse = 0.0;
+        for i in 0..states.len() {
+            let xi: Vec<_> = x.iter().enumerate().filter(|&(k, _)| k != i).map(|(_, r)| r.clone()).collect();
+            let yi: Vec<_> = y.iter().enumerate().filter(|&(k, _)| k != i).map(|(_, &e)| e).collect();
+            let params = least_squares(&xi, &yi).context("leave-one-out fitting")?;
+            let e: f64 = x[i].iter().zip(params.iter()).map(|(a, b)| a * b).sum();
+            sse += (e - y[i]).powi(2);
+        }
+
+        Ok((sse / states.len() as f64).sqrt())
+    }
+}
+
+fn design_row(shells: &NeighborShells, spins: &SpinStates, so: &[u8]) -> Vec<f64> {
+    let mut row = vec![1.0];
+    row.extend(shells.descriptors(&spin_values(spins, so)));
+    row
+}
+
+fn spin_values(spins: &SpinStates, so: &[u8]) -> Vec<[f64; 3]> {
+    so.iter().map(|&s| spins.moment(s)).collect()
+}
+
+/// Solve linear least-squares problem min |X b - y| using normal equations.
+fn least_squares(x: &[Vec<f64>], y: &[f64]) -> Result<Vec<f64>> {
+    let n = x.first().map_or(0, |r| r.len());
+    if x.len() < n {
+        bail!("underdetermined least-squares problem: {} equations for {} unknowns", x.len(), n);
+    }
+
+    // augmented matrix [X^T X | X^T y]
+    let mut a = vec![vec![0.0; n + 1]; n];
+    for (row, &yk) in x.iter().zip(y.iter()) {
+        for (ai, &xi) in a.iter_mut().zip(row.iter()) {
+            for (aij, &xj) in ai.iter_mut().zip(row.iter()) {
+                *aij += xi * xj;
+            }
+            ai[n] += xi * yk;
+        }
+    }
+
+    // gaussian elimination with partial pivoting
+    for k in 0..n {
+        let p = (k..n).max_by(|&i, &j| a[i][k].abs().total_cmp(&a[j][k].abs())).unwrap();
+        if a[p][k].abs() < 1e-10 {
+            bail!("singular least-squares problem: descriptors are linearly dependent");
+        }
+        a.swap(k, p);
+        let (upper, lower) = a.split_at_mut(k + 1);
+        let ak = &upper[k];
+        for ai in lower.iter_mut() {
+            let f = ai[k] / ak[k];
+            for (aij, akj) in ai[k..].iter_mut().zip(ak[k..].iter()) {
+                *aij -= f * akj;
+            }
+        }
+    }
+    let mut b = vec![0.0; n];
+    for k in (0..n).rev() {
+        let s: f64 = (k + 1..n).map(|j| a[k][j] * b[j]).sum();
+        b[k] = (a[k][n] - s) / a[k][k];
+    }
+
+    Ok(b)
+}
+
+fn image_translations(r: i32) -> impl Iterator<Item = [f64; 3]> {
+    (-r..=r).flat_map(move |i| (-r..=r).flat_map(move |j| (-r..=r).map(move |k| [i as f64, j as f64, k as f64])))
+}
+
+fn norm(v: [f64; 3]) -> f64 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+// e93b6a5f ends here
+
+// [[file:../magman.note::a4d07f35][a4d07f35]]
+/// Fit exchange model with `nshells` neighbor shells using all magnetic
+/// states in database, and report fitted couplings and errors.
+pub fn fit_from_db(nshells: usize) -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG;
+    let poscar = Poscar::from_file(config.vasp.template_directory().join("POSCAR"))?;
+    let sites = &crate::config::magnetic_sites()?.sites;
+    let shells = NeighborShells::from_poscar(&poscar, sites, nshells)?;
+
+    let mut states = MagneticState::list_all()?;
+    let nflipped = states.iter().filter(|ms| ms.spin_flipped).count();
+    if nflipped > 0 {
+        println!("Skip {} items relaxed into different spin-orderings.", nflipped);
+        states.retain(|ms| !ms.spin_flipped);
+    }
+    states.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+    println!("Fit exchange model using {} magnetic states.", states.len());
+    let model = ExchangeModel::fit(shells, config.vasp.spin_states(), &states)?;
+
+    println!("{:^6} {:^12} {:^12}", "shell", "distance", "J (meV)");
+    for (i, (d, j)) in model.shells.distances().iter().zip(model.couplings.iter()).enumerate() {
+        println!("{:^6} {:^12.4} {:^-12.3}", format!("J{}", i + 1), d, j * 1000.0);
+    }
+    println!("E0 = {:-.5} eV", model.e0);
+
+    println!("{:^width$} {:^14} {:^14} {:^10}", "key", "energy", "predicted", "residual", width = sites.len());
+    let mut sse = 0.0;
+    for ms in states.iter() {
+        let e = model.predict(&ms.spin_ordering);
+        sse += (e - ms.energy).powi(2);
+        println!("{} {:-14.5} {:-14.5} {:-10.5}", ms.spin_key(), ms.energy, e, e - ms.energy);
+    }
+    println!("RMSE = {:.5} eV", (sse / states.len() as f64).sqrt());
+    match model.loocv_error(&states) {
+        Ok(cv) => println!("LOOCV RMSE = {:.5} eV", cv),
+        Err(e) => warn!("leave-one-out cross validation failed: {:?}", e),
+    }
+
+    Ok(())
+}
+// a4d07f35 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_exchange_model_fit() -> Result<()> {
+    let poscar = Poscar::from_file("tests/files/template/POSCAR")?;
+    let sites: Vec<_> = (0..12).collect();
+    let shells = NeighborShells::from_poscar(&poscar, &sites, 3)?;
+    let spins = SpinStates::default();
+    assert_eq!(shells.nshells(), 3);
+    assert!(shells.distances()[0] < shells.distances()[1]);
+
+    // ferromagnetic ordering: Σ s_i s_j equals the number of bonds
+    let nbonds = shells.descriptors(&[[0.0, 0.0, 1.0]; 12]);
+    assert!(nbonds.iter().all(|&x| x > 0.0 && x.fract() == 0.0));
+
+    // recover couplings from synthetic energies
+    let j_ref = [0.030, -0.012, 0.005];
+    let states: Vec<_> = (0..40u32)
+        .map(|i| {
+            let so: Vec<u8> = (0..12).map(|k| (k == 0 || (i.wrapping_mul(2654435761) >> k) & 1 == 1) as u8).collect();
+            let x = shells.descriptors(&spin_values(&spins, &so));
+            let energy = -200.0 + x.iter().zip(j_ref.iter()).map(|(x, j)| x * j).sum::<f64>();
+            MagneticState::new(&so, energy)
+        })
+        .collect();
+    let model = ExchangeModel::fit(shells, &spins, &states)?;
+    assert!((model.e0 + 200.0).abs() < 1e-6);
+    for (j, j0) in model.couplings.iter().zip(j_ref.iter()) {
+        assert!((j - j0).abs() < 1e-6);
+    }
+    assert!(model.loocv_error(&states)? < 1e-6);
+
+    // spin-flipped states are not used in fitting
+    let mut flipped = MagneticState::new(&states[0].spin_ordering, 0.0);
+    flipped.spin_flipped = true;
+    let states: Vec<_> = std::iter::once(flipped).chain(states).collect();
+    let model = ExchangeModel::fit(model.shells, &spins, &states)?;
+    assert!((model.e0 + 200.0).abs() < 1e-6);
+    assert!(model.loocv_error(&states)? < 1e-6);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,215 @@
+// [[file:../magman.note::61d0c8a4][61d0c8a4]]
+use super::*;
+use crate::MAG_DB_CONNECTION;
+
+use gosh::db::prelude::*;
+use std::path::Path;
+// 61d0c8a4 ends here
+
+// [[file:../magman.note::b83e47f2][b83e47f2]]
+/// The banner written at the end of OUTCAR when VASP terminates normally.
+const OUTCAR_BANNER: &str = "General timing and accounting informations for this job";
+
+/// Fatal error messages of VASP in `vasp.out`.
+const ERROR_PATTERNS: &[&str] = &[
+    "ZBRENT: fatal error",
+    "BRMIX: very serious problems",
+    "Error EDDDAV",
+    "EDDRMM: call to ZHEGV failed",
+    "LAPACK: Routine ZPOTRF failed",
+    "Sub-Space-Matrix is not hermitian",
+    "VERY BAD NEWS! internal error",
+    "TOO FEW BANDS",
+    "Segmentation fault",
+    "BAD TERMINATION",
+    "KILLED BY SIGNAL",
+];
+
+/// The class of failed VASP job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// Terminated without error message, such as killed by job scheduler.
+    Unfinished,
+    /// Electronic steps reached NELM without convergence.
+    NotConverged,
+    /// Terminated with error message in `vasp.out`.
+    Crashed,
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Unfinished => "unfinished",
+            Self::NotConverged => "not converged",
+            Self::Crashed => "crashed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Error for VASP job finished abnormally, whose energy can not be trusted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobFailure {
+    pub status: FailureKind,
+    pub reason: String,
+    /// Recovery attempts before giving up.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<crate::recovery::Attempt>,
+}
+
+impl std::fmt::Display for JobFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.reason)
+    }
+}
+
+impl std::error::Error for JobFailure {}
+
+impl JobFailure {
+    fn new(status: FailureKind, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+            attempts: vec![],
+        }
+    }
+}
+
+/// Inspect output files in VASP job directory `adir` for normal
+/// termination and electronic convergence of each ionic step.
+pub fn inspect_job(adir: &Path) -> std::result::Result<(), JobFailure> {
+    let outcar = gut::fs::read_file(adir.join("OUTCAR")).unwrap_or_default();
+    if !outcar.contains(OUTCAR_BANNER) {
+        let vasp_out = gut::fs::read_file(adir.join("vasp.out")).unwrap_or_default();
+        let error = vasp_out.lines().find(|line| ERROR_PATTERNS.iter().any(|p| line.contains(p)));
+        return Err(match error {
+            Some(line) => JobFailure::new(FailureKind::Crashed, line.trim()),
+            None => JobFailure::new(FailureKind::Unfinished, "no termination banner in OUTCAR"),
+        });
+    }
+
+    // such as "   NELM   =     60;   NELMIN=  5; NELMDL=-12"
+    let nelm = outcar
+        .lines()
+        .find(|line| line.trim_start().starts_with("NELM "))
+        .and_then(|line| line.split(['=', ';']).nth(1)?.trim().parse::<usize>().ok());
+    let oszicar = gut::fs::read_file(adir.join("OSZICAR")).unwrap_or_default();
+    if let Some(nelm) = nelm {
+        for (i, n) in electronic_steps(&oszicar).into_iter().enumerate() {
+            if n >= nelm {
+                let reason = format!("ionic step {} reached NELM = {}", i + 1, nelm);
+                return Err(JobFailure::new(FailureKind::NotConverged, reason));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the number of electronic steps in each ionic step from OSZICAR
+/// content `s`.
+fn electronic_steps(s: &str) -> Vec<usize> {
+    let mut steps = vec![];
+    let mut n = 0;
+    for line in s.lines() {
+        if line.contains("F=") {
+            steps.push(n);
+            n = 0;
+        } else if let Some((algo, rest)) = line.split_once(':') {
+            if ["DAV", "RMM", "CG", "SDA"].contains(&algo.trim()) {
+                n = rest.split_whitespace().next().and_then(|x| x.parse().ok()).unwrap_or(n);
+            }
+        }
+    }
+    steps
+}
+
+/// Failed evaluation of spin-ordering saved in database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedState {
+    pub spin_ordering: Vec<u8>,
+    #[serde(flatten)]
+    pub failure: JobFailure,
+    /// Unix timestamp in seconds when evaluated.
+    pub timestamp: Option<u64>,
+}
+
+impl FailedState {
+    pub fn new(so: &[u8], failure: JobFailure) -> Self {
+        Self {
+            spin_ordering: so.to_vec(),
+            failure,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|t| t.as_secs()),
+        }
+    }
+
+    /// Save into default database.
+    pub fn save(&self) -> Result<()> {
+        let key = crate::magmom::spin_key(&self.spin_ordering);
+        warn!("job {} failed: {}", key, self.failure);
+        self.put_into_collection(&MAG_DB_CONNECTION, &key)?;
+
+        Ok(())
+    }
+
+    /// Load failed state of `key` from database, or None if not failed.
+    pub fn load(key: &str) -> Result<Option<Self>> {
+        crate::magmom::load_from_db(key)
+    }
+
+    /// Return all failed states in database.
+    pub fn list_all() -> Result<Vec<Self>> {
+        let items = Self::list_collection(&MAG_DB_CONNECTION)?;
+        Ok(items)
+    }
+
+    /// Remove from default database, so that the spin-ordering will be
+    /// evaluated again.
+    pub fn remove(&self) -> Result<()> {
+        let key = crate::magmom::spin_key(&self.spin_ordering);
+        Self::del_from_collection(&MAG_DB_CONNECTION, &key).with_context(|| format!("remove failed state {key}"))?;
+
+        Ok(())
+    }
+}
+// b83e47f2 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_inspect_job() -> Result<()> {
+    assert!(inspect_job("tests/files/jobs/100100001001".as_ref()).is_ok());
+    let oszicar = gut::fs::read_file("tests/files/jobs/100100001001/OSZICAR")?;
+    assert_eq!(electronic_steps(&oszicar), vec![24]);
+
+    let dir = tempfile::tempdir()?;
+    let adir = dir.path();
+    let failure = inspect_job(adir).unwrap_err();
+    assert_eq!(failure.status, FailureKind::Unfinished);
+
+    gut::fs::write_to_file(adir.join("vasp.out"), "RMM:  3 ...\n ZBRENT: fatal error in bracketing\n")?;
+    let failure = inspect_job(adir).unwrap_err();
+    assert_eq!(failure.status, FailureKind::Crashed);
+    assert_eq!(failure.reason, "ZBRENT: fatal error in bracketing");
+
+    let outcar = format!("   NELM   =      3;   NELMIN=  2; NELMDL= -5\n {}:\n", OUTCAR_BANNER);
+    gut::fs::write_to_file(adir.join("OUTCAR"), &outcar)?;
+    let oszicar = "DAV:   1  -0.1E+03\nDAV:   2  -0.2E+03\n   1 F= -.2E+03 E0= -.2E+03\nDAV:   1  -0.1E+03\nDAV:   2  -0.2E+03\nDAV:   3  -0.2E+03\n   2 F= -.2E+03 E0= -.2E+03\n";
+    gut::fs::write_to_file(adir.join("OSZICAR"), oszicar)?;
+    let failure = inspect_job(adir).unwrap_err();
+    assert_eq!(failure.status, FailureKind::NotConverged);
+    assert_eq!(failure.to_string(), "not converged: ionic step 2 reached NELM = 3");
+
+    // failed state is kept until cleared
+    let failed = FailedState::new(&[1, 0, 2, 0, 1], failure);
+    failed.save()?;
+    assert!(FailedState::load("10201")?.is_some());
+    failed.remove()?;
+    assert!(FailedState::load("10201")?.is_none());
+
+    Ok(())
+}
+// test:1 ends here
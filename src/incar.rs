@@ -0,0 +1,221 @@
+// [[file:../magman.note::7c3e9d12][7c3e9d12]]
+use super::*;
+use std::path::Path;
+// 7c3e9d12 ends here
+
+// [[file:../magman.note::e48a0b5f][e48a0b5f]]
+/// A line in INCAR file, which may contain multiple tags separated by `;`
+/// and a trailing comment starting with `!` or `#`.
+#[derive(Debug, Clone, PartialEq)]
+struct Line {
+    /// Leading whitespaces.
+    indent: String,
+    /// Tag names in upper case and their values.
+    tags: Vec<(String, String)>,
+    /// Trailing comment including the leading `!` or `#`.
+    comment: Option<String>,
+    /// The original text, or None if the line has been modified.
+    text: Option<String>,
+}
+
+impl Line {
+    fn parse(text: &str) -> Self {
+        let p = text.find(['!', '#']).unwrap_or(text.len());
+        let (content, comment) = text.split_at(p);
+        let indent = content.len() - content.trim_start().len();
+        let tags = content
+            .split(';')
+            .filter_map(|part| {
+                let (tag, value) = part.split_once('=')?;
+                let tag = tag.trim();
+                (!tag.is_empty()).then(|| (tag.to_uppercase(), value.trim().to_string()))
+            })
+            .collect();
+
+        Self {
+            indent: content[..indent].to_string(),
+            tags,
+            comment: (!comment.is_empty()).then(|| comment.to_string()),
+            text: Some(text.to_string()),
+        }
+    }
+
+    fn format(&self) -> String {
+        if let Some(text) = &self.text {
+            return text.clone();
+        }
+        let tags: Vec<_> = self.tags.iter().map(|(tag, value)| format!("{} = {}", tag, value)).collect();
+        let mut line = format!("{}{}", self.indent, tags.join("; "));
+        if let Some(comment) = &self.comment {
+            line = format!("{} {}", line, comment);
+        }
+        line
+    }
+}
+
+/// VASP INCAR file, keeping comments and layout of lines for writing back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incar {
+    lines: Vec<Line>,
+}
+
+impl Incar {
+    /// Read INCAR from file in `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = gut::fs::read_file(path).with_context(|| format!("Failed to open VASP INCAR: {}", path.display()))?;
+        Ok(Self::parse(&s))
+    }
+
+    /// Parse INCAR from content in `s`.
+    pub fn parse(s: &str) -> Self {
+        Self {
+            lines: s.lines().map(Line::parse).collect(),
+        }
+    }
+
+    /// Return all tags with values in file order.
+    pub fn tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines
+            .iter()
+            .flat_map(|line| line.tags.iter().map(|(tag, value)| (tag.as_str(), value.as_str())))
+    }
+
+    /// Return the value of the first occurrence of `tag`.
+    pub fn get(&self, tag: &str) -> Option<&str> {
+        let tag = tag.to_uppercase();
+        self.tags().find(|(t, _)| *t == tag).map(|(_, v)| v)
+    }
+
+    /// Set `tag` to `value`. The first occurrence of `tag` is updated in
+    /// place, and the others are removed. The tag is appended at the end if
+    /// not found.
+    pub fn set(&mut self, tag: &str, value: &str) {
+        let tag = tag.to_uppercase();
+        let value = value.trim();
+        let mut found = false;
+        for line in self.lines.iter_mut() {
+            if !line.tags.iter().any(|(t, _)| *t == tag) {
+                continue;
+            }
+            line.text = None;
+            line.tags.retain_mut(|(t, v)| {
+                if *t != tag {
+                    true
+                } else if !found {
+                    found = true;
+                    *v = value.to_string();
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        self.lines.retain(|line| line.text.is_some() || !line.tags.is_empty() || line.comment.is_some());
+        if !found {
+            self.lines.push(Line {
+                indent: String::new(),
+                tags: vec![(tag, value.to_string())],
+                comment: None,
+                text: None,
+            });
+        }
+    }
+
+    /// Remove all occurrences of `tag`, together with the lines left
+    /// without any tag. Return false if not found.
+    pub fn remove(&mut self, tag: &str) -> bool {
+        let tag = tag.to_uppercase();
+        let mut found = false;
+        self.lines.retain_mut(|line| {
+            if !line.tags.iter().any(|(t, _)| *t == tag) {
+                return true;
+            }
+            found = true;
+            line.text = None;
+            line.tags.retain(|(t, _)| *t != tag);
+            !line.tags.is_empty()
+        });
+        found
+    }
+
+    /// Return tags occurring more than once, with their values in file
+    /// order.
+    pub fn duplicate_tags(&self) -> Vec<(String, Vec<String>)> {
+        let mut all: Vec<(String, Vec<String>)> = vec![];
+        for (tag, value) in self.tags() {
+            match all.iter_mut().find(|(t, _)| t == tag) {
+                Some((_, values)) => values.push(value.to_string()),
+                None => all.push((tag.to_string(), vec![value.to_string()])),
+            }
+        }
+        all.retain(|(_, values)| values.len() > 1);
+        all
+    }
+
+    /// Return duplicated tags with different values, which are ambiguous.
+    pub fn conflicting_tags(&self) -> Vec<(String, Vec<String>)> {
+        let mut tags = self.duplicate_tags();
+        tags.retain(|(_, values)| values.iter().any(|v| !v.eq_ignore_ascii_case(&values[0])));
+        tags
+    }
+}
+
+impl std::fmt::Display for Incar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in self.lines.iter() {
+            writeln!(f, "{}", line.format())?;
+        }
+        Ok(())
+    }
+}
+// e48a0b5f ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_incar() -> Result<()> {
+    let txt = gut::fs::read_file("tests/files/template/INCAR")?;
+    let mut incar = Incar::parse(&txt);
+    assert_eq!(incar.get("encut"), Some("500"));
+    assert_eq!(incar.get("SYSTEM"), Some("fe2o3 cell"));
+    assert_eq!(incar.get("LORBIT"), Some("11"));
+    assert_eq!(incar.get("EDIFF"), Some("1E-05"));
+    // trailing comments are stripped, and "#LREAL=.FALSE." is ignored
+    assert_eq!(incar.get("LREAL"), Some("Auto"));
+    // commented out
+    assert_eq!(incar.get("NPAR"), None);
+    // unchanged lines are kept as they are
+    assert_eq!(incar.to_string().lines().collect::<Vec<_>>(), txt.lines().collect::<Vec<_>>());
+
+    // LMAXMIX appears twice with the same value
+    assert_eq!(incar.duplicate_tags(), vec![("LMAXMIX".to_string(), vec!["4".to_string(), "4".to_string()])]);
+    assert!(incar.conflicting_tags().is_empty());
+
+    incar.set("MAGMOM", " 5.0 -5.0 28*0.0");
+    incar.set("LMAXMIX", "6");
+    incar.set("ISPIN", "2");
+    incar.set("SAXIS", "0 0 1");
+    let new = incar.to_string();
+    assert!(new.contains("\n  MAGMOM = 5.0 -5.0 28*0.0\n"));
+    assert!(new.contains("\n LMAXMIX = 6 !6 for f, 4 for d\n"));
+    assert!(new.ends_with("\nSAXIS = 0 0 1\n"));
+    assert!(new.contains("#d-electron\n"));
+    let incar = Incar::parse(&new);
+    assert!(incar.duplicate_tags().is_empty());
+    assert_eq!(incar.get("magmom"), Some("5.0 -5.0 28*0.0"));
+    assert_eq!(incar.get("SYSTEM"), Some("fe2o3 cell"));
+
+    // multiple tags in one line
+    let mut incar = Incar::parse("ISPIN = 1; ENCUT = 400 # cutoff\nALGO = Fast; ISPIN = 2");
+    assert_eq!(incar.get("ENCUT"), Some("400"));
+    assert_eq!(incar.conflicting_tags(), vec![("ISPIN".to_string(), vec!["1".to_string(), "2".to_string()])]);
+    incar.set("ISPIN", "2");
+    assert_eq!(incar.to_string(), "ISPIN = 2; ENCUT = 400 # cutoff\nALGO = Fast\n");
+    assert!(incar.remove("encut"));
+    assert!(!incar.remove("ENCUT"));
+    assert!(incar.remove("ALGO"));
+    assert_eq!(incar.to_string(), "ISPIN = 2 # cutoff\n");
+
+    Ok(())
+}
+// test:1 ends here
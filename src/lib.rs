@@ -10,9 +10,22 @@ use gut::prelude::*;
 // 1c2c22e4 ends here
 
 // [[file:../magman.note::25e28290][25e28290]]
+mod check;
 mod config;
+mod exchange;
+mod failure;
+mod incar;
 mod magmom;
+mod operators;
+mod poscar;
+mod recovery;
+mod replay;
+mod scheduler;
+mod screening;
 mod search;
+mod spin;
+mod symmetry;
+mod table;
 mod vasp;
 // 25e28290 ends here
 
@@ -43,10 +56,16 @@ pub fn wait_file(f: &std::path::Path, timeout: usize) -> Result<()> {
 }
 
 
+/// Environment variable for the database url used by gosh.
+const DB_VAR: &str = "GOSH_DATABASE_URL";
+
 // global database connection
 lazy_static! {
     static ref MAG_DB_CONNECTION: gosh::db::DbConnection = {
-        let dbvar = "GOSH_DATABASE_URL";
+        let dbvar = DB_VAR;
+        // tests never write into the database in working directory
+        #[cfg(test)]
+        std::env::set_var(dbvar, TEST_DB_DIR.path().join("magman-test.db"));
         let default_db = format!("{}.db", env!("CARGO_PKG_NAME"));
         if std::env::var(dbvar).is_err() {
             info!("Use default db file: {}", default_db);
@@ -57,17 +76,153 @@ lazy_static! {
     };
 }
 
+#[cfg(test)]
+lazy_static! {
+    /// Temporary directory holding the database shared by all tests.
+    static ref TEST_DB_DIR: tempfile::TempDir = tempfile::tempdir().expect("tempdir for test db");
+}
+
+/// Set the path to database file. This should be called before accessing
+/// the database.
+pub fn set_database(path: &std::path::Path) {
+    std::env::set_var(DB_VAR, path);
+}
+
 pub fn list_db() -> Result<()> {
     magmom::MagneticState::list_db()?;
 
     Ok(())
 }
 
-/// Collect results from finished jobs in working directory.
-pub fn collect_results_from_dir(d: &std::path::Path) -> Result<()> {
+/// Show details of item `key` in database.
+pub fn show_item(key: &str) -> Result<()> {
+    magmom::MagneticState::show(key)?;
+
+    Ok(())
+}
+
+/// Export all items in database in `format` ("csv" or "json") into
+/// `output`, or stdout if it is None.
+pub fn export_db(format: &str, output: Option<&std::path::Path>) -> Result<()> {
+    let txt = table::export_db(format)?;
+    match output {
+        Some(path) => gut::fs::write_to_file(path, &txt)?,
+        None => println!("{}", txt),
+    }
+
+    Ok(())
+}
+
+/// Import items from csv table file or job directories in `path` into
+/// database.
+pub fn import_into_db(path: &std::path::Path, force: bool, tolerance: f64) -> Result<()> {
+    table::import(path, force, tolerance)?;
+
+    Ok(())
+}
+
+/// Show status of database and job directories.
+pub fn show_status() -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG;
+    println!("config file: {}", config::config_file());
+    // the default database is resolved on first access
+    lazy_static::initialize(&MAG_DB_CONNECTION);
+    println!("database: {}", std::env::var(DB_VAR).unwrap_or_default());
+    if let Some(replay) = &config.replay {
+        println!("replay table: {}", replay.table.display());
+    }
+
+    let items = magmom::MagneticState::list_all()?;
+    println!("{} items in database.", items.len());
+    if let Some(ms) = items.iter().min_by(|a, b| a.energy.total_cmp(&b.energy)) {
+        println!("lowest energy: {} => {:-12.4}", ms.spin_key(), ms.energy);
+    }
+
+    let failed: Vec<_> = failure::FailedState::list_all()?
+        .into_iter()
+        // refinement of screened items may fail
+        .filter(|x| items.iter().all(|ms| ms.spin_ordering != x.spin_ordering || ms.screening_only))
+        .collect();
+    if !failed.is_empty() {
+        println!("{} failed items in database:", failed.len());
+        for x in failed.iter() {
+            println!("{} => {}", magmom::spin_key(&x.spin_ordering), x.failure);
+        }
+    }
+
+    if let Some(run) = search::SearchRun::list_all()?.last() {
+        println!("last search: {} with random seed {}", run.strategy, run.random_seed);
+    }
+
+    let jobs = config.vasp.list_job_directories()?;
+    let nfinished = jobs.iter().filter(|(_, done)| *done).count();
+    println!("{} job directories: {} finished, {} unfinished.", jobs.len(), nfinished, jobs.len() - nfinished);
+
+    Ok(())
+}
+
+/// Check VASP template directory before running jobs, and print all
+/// problems found as plain text or in json format.
+pub fn check_template(json: bool) -> Result<()> {
+    let report = check::check_config(crate::config::config_file().as_ref());
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report);
+    }
+    if !report.is_ok() {
+        bail!("{} errors found in template check", report.nerrors());
+    }
+
+    Ok(())
+}
+
+/// Remove unfinished job directories in working directory, and also in
+/// screening working directory if enabled. If `failed` is true, also clear
+/// failed spin-orderings recorded in database together with their job
+/// directories, so that they will be evaluated again instead of being
+/// skipped in later searches.
+pub fn clean_jobs(dry_run: bool, failed: bool) -> Result<()> {
+    let vasp = &crate::config::MAGMAN_CONFIG.vasp;
+    for (adir, _) in vasp.list_job_directories()?.into_iter().filter(|(_, done)| !done) {
+        println!("removing {}", adir.display());
+        if !dry_run {
+            std::fs::remove_dir_all(&adir).with_context(|| format!("remove {}", adir.display()))?;
+        }
+    }
+
+    if failed {
+        for x in failure::FailedState::list_all()? {
+            println!("clearing failed {} => {}", magmom::spin_key(&x.spin_ordering), x.failure);
+            for adir in vasp.job_directories(&x.spin_ordering).into_iter().filter(|d| d.is_dir()) {
+                println!("removing {}", adir.display());
+                if !dry_run {
+                    std::fs::remove_dir_all(&adir).with_context(|| format!("remove {}", adir.display()))?;
+                }
+            }
+            if !dry_run {
+                x.remove()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fit exchange model with `nshells` neighbor shells using items in database.
+pub fn fit_exchange_model(nshells: usize) -> Result<()> {
+    exchange::fit_from_db(nshells)?;
+
+    Ok(())
+}
+
+/// Collect results from finished jobs in directory `d`, or in working
+/// directory if it is None.
+pub fn collect_results_from_dir(d: Option<&std::path::Path>) -> Result<()> {
     let vasp = &crate::config::MAGMAN_CONFIG.vasp;
-    debug!("collecting results ...");
-    for ms in vasp.collect_results()? {
+    let d = d.unwrap_or_else(|| vasp.working_directory());
+    debug!("collecting results from {} ...", d.display());
+    for ms in vasp.collect_results(d)? {
         ms.save().unwrap_or_else(|e| {
             error!("{}", e);
         });
@@ -4,63 +4,338 @@ use serde::*;
 use super::*;
 use crate::MAG_DB_CONNECTION;
 
+use crate::spin::SpinStates;
 use gosh::db::prelude::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MagneticState {
-    pub spin_ordering: Vec<bool>,
+    /// Spin state of each magnetic site.
+    #[serde(deserialize_with = "deserialize_spins")]
+    pub spin_ordering: Vec<u8>,
     pub energy: f64,
+    /// Energy from screening calculation in two-stage evaluation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screening_energy: Option<f64>,
+    /// True if only screening calculation is done, and `energy` is the
+    /// screening energy.
+    #[serde(default)]
+    pub screening_only: bool,
+    /// Converged magnetic moment of each magnetic site.
+    pub magnetic_moments: Option<Vec<f64>>,
+    /// Converged magnetic moment vector of each magnetic site in the SAXIS
+    /// frame, for non-collinear calculation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub magnetic_moment_vectors: Option<Vec<[f64; 3]>>,
+    /// Converged spin-ordering derived from magnetic moments.
+    #[serde(default, deserialize_with = "deserialize_optional_spins")]
+    pub converged_ordering: Option<Vec<u8>>,
+    /// True if calculation relaxed into a spin-ordering different from the
+    /// requested one.
+    #[serde(default)]
+    pub spin_flipped: bool,
+    /// Unix timestamp in seconds when evaluated.
+    pub timestamp: Option<u64>,
+    /// Recovery attempts of failed VASP job.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<crate::recovery::Attempt>,
+}
+
+/// Spin-ordering saved in database. Spin-up and spin-down were saved as
+/// `true` and `false` in old versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedSpins {
+    States(Vec<u8>),
+    Binary(Vec<bool>),
+}
+
+impl From<SavedSpins> for Vec<u8> {
+    fn from(spins: SavedSpins) -> Self {
+        match spins {
+            SavedSpins::States(so) => so,
+            SavedSpins::Binary(so) => so.into_iter().map(|b| b as u8).collect(),
+        }
+    }
+}
+
+fn deserialize_spins<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error> {
+    SavedSpins::deserialize(deserializer).map(Into::into)
+}
+
+fn deserialize_optional_spins<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Vec<u8>>, D::Error> {
+    Option::<SavedSpins>::deserialize(deserializer).map(|x| x.map(Into::into))
+}
+
+lazy_static! {
+    /// Keys of spin-orderings being evaluated.
+    static ref EVALUATING: (std::sync::Mutex<std::collections::HashSet<String>>, std::sync::Condvar) = Default::default();
+}
+
+/// Exclusive evaluation of a spin-ordering. Individuals in a generation are
+/// evaluated in parallel, and symmetry-equivalent ones share the same key
+/// and job directory.
+struct EvaluationGuard(String);
+
+impl EvaluationGuard {
+    /// Wait until no other thread is evaluating `key`.
+    fn acquire(key: &str) -> Self {
+        let (lock, cvar) = &*EVALUATING;
+        let mut keys = lock.lock().expect("evaluating keys");
+        while keys.contains(key) {
+            keys = cvar.wait(keys).expect("evaluating keys");
+        }
+        keys.insert(key.to_string());
+        Self(key.to_string())
+    }
+}
+
+impl Drop for EvaluationGuard {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*EVALUATING;
+        if let Ok(mut keys) = lock.lock() {
+            keys.remove(&self.0);
+        }
+        cvar.notify_all();
+    }
 }
 
 pub trait EvaluateMagneticState {
+    /// Map spin-ordering `so` to the representative of its
+    /// symmetry-equivalent spin-orderings. No symmetry is considered by
+    /// default.
+    fn canonicalize(&self, so: &[u8]) -> Vec<u8> {
+        so.to_vec()
+    }
+
     /// Evaluate with caching.
-    fn evaluate(&self, so: &[bool]) -> Result<MagneticState> {
-        let key = binary_key(so);
-        match crate::magmom::MagneticState::get_from_collection(&MAG_DB_CONNECTION, &key) {
-            Ok(ms) => Ok(ms),
-            // FIXME: handle not-found error
-            Err(_) => {
-                let ms = self.evaluate_new(so).with_context(|| format!("evaluate {key}"))?;
-                ms.put_into_collection(&MAG_DB_CONNECTION, &key)
-                    .with_context(|| format!("put {key} into db"))?;
-                Ok(ms)
-            }
+    fn evaluate(&self, so: &[u8]) -> Result<MagneticState> {
+        let so = &self.canonicalize(so);
+        let key = spin_key(so);
+        // the equivalent one being evaluated will be found in database
+        let _guard = EvaluationGuard::acquire(&key);
+        if let Some(ms) = MagneticState::load(&key)? {
+            return Ok(ms);
         }
+        // failed jobs will not be retried until cleared with `magman clean
+        // --failed`
+        if let Some(failed) = crate::failure::FailedState::load(&key)? {
+            return Err(gut::prelude::Error::new(failed.failure).context(format!("{key} failed before")));
+        }
+        let ms = self.evaluate_new(so).map_err(|e| {
+            // record failed job in database
+            if let Some(failure) = e.downcast_ref::<crate::failure::JobFailure>() {
+                if let Err(e) = crate::failure::FailedState::new(so, failure.clone()).save() {
+                    error!("{:?}", e);
+                }
+            }
+            e.context(format!("evaluate {key}"))
+        })?;
+        ms.put_into_collection(&MAG_DB_CONNECTION, &key)
+            .with_context(|| format!("put {key} into db"))?;
+        Ok(ms)
     }
 
     /// Evaluate new item.
-    fn evaluate_new(&self, so: &[bool]) -> Result<MagneticState>;
+    fn evaluate_new(&self, so: &[u8]) -> Result<MagneticState>;
+
+    /// Return all evaluated items, which are cached in database by default.
+    fn list_evaluated(&self) -> Result<Vec<MagneticState>> {
+        MagneticState::list_all()
+    }
 }
 
 impl MagneticState {
-    pub fn new(so: &[bool], energy: f64) -> Self {
+    pub fn new(so: &[u8], energy: f64) -> Self {
         Self {
             spin_ordering: so.to_owned(),
             energy,
+            screening_energy: None,
+            screening_only: false,
+            magnetic_moments: None,
+            magnetic_moment_vectors: None,
+            converged_ordering: None,
+            spin_flipped: false,
+            attempts: vec![],
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|t| t.as_secs()),
+        }
+    }
+
+    /// Set converged magnetic moments of magnetic sites from `moments` of
+    /// all atoms in POSCAR order, with magnetic sites at atom indices
+    /// `sites` and their initial MAGMOM magnitudes `magmoms`. A magnetic
+    /// site with moment smaller than `threshold` in magnitude, with reversed
+    /// sign, or closer in magnitude to another spin state, such as
+    /// high-spin relaxed into low-spin, is considered as a spin flip.
+    /// Reversing all spins leads to the equivalent spin-ordering, and is not
+    /// a flip.
+    pub fn set_magnetic_moments(
+        &mut self,
+        moments: &[f64],
+        sites: &[usize],
+        magmoms: &[f64],
+        threshold: f64,
+        spins: &SpinStates,
+    ) -> Result<()> {
+        let moments = select_sites(moments, sites, self.spin_ordering.len())?;
+        let vectors: Vec<_> = moments.iter().map(|&m| [0.0, 0.0, m]).collect();
+        self.set_site_moments(vectors, magmoms, threshold, spins)?;
+        self.magnetic_moment_vectors = None;
+        self.magnetic_moments = Some(moments);
+
+        Ok(())
+    }
+
+    /// Set converged magnetic moment vectors of magnetic sites in the SAXIS
+    /// frame from non-collinear calculation, given `moments` of all atoms
+    /// in POSCAR order, atom indices of magnetic `sites` and their initial
+    /// MAGMOM magnitudes `magmoms`. Each site converges into the spin state
+    /// closest in direction, and in magnitude among states in the same
+    /// direction.
+    pub fn set_magnetic_moment_vectors(
+        &mut self,
+        moments: &[[f64; 3]],
+        sites: &[usize],
+        magmoms: &[f64],
+        threshold: f64,
+        spins: &SpinStates,
+    ) -> Result<()> {
+        let moments = select_sites(moments, sites, self.spin_ordering.len())?;
+        self.set_site_moments(moments, magmoms, threshold, spins)
+    }
+
+    /// Set converged spin-ordering from moment vectors of each magnetic
+    /// site, with spin states scaled by initial MAGMOM magnitudes
+    /// `magmoms` of each site.
+    fn set_site_moments(&mut self, moments: Vec<[f64; 3]>, magmoms: &[f64], threshold: f64, spins: &SpinStates) -> Result<()> {
+        if magmoms.len() != moments.len() {
+            bail!("found {} MAGMOM values for {} magnetic sites", magmoms.len(), moments.len());
         }
+        let norm = |v: &[f64; 3]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let cosine = |a: &[f64; 3], b: &[f64; 3]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>() / (norm(a) * norm(b));
+        let states: Vec<_> = (0..spins.nstates() as u8).collect();
+        let mut collapsed = false;
+        let converged: Vec<_> = self
+            .spin_ordering
+            .iter()
+            .zip(moments.iter().zip(magmoms))
+            .map(|(&s, (m, &magmom))| {
+                let v = spins.moment(s);
+                if norm(m) < threshold {
+                    collapsed |= norm(&v) != 0.0;
+                    // collapsed into the nonmagnetic state if available
+                    states.iter().copied().find(|&t| norm(&spins.moment(t)) == 0.0).unwrap_or(s)
+                } else {
+                    // the states closest in direction of the moment
+                    let magnetic = states.iter().copied().filter(|&t| norm(&spins.moment(t)) > 0.0);
+                    let cmax = magnetic.clone().map(|t| cosine(&spins.moment(t), m)).fold(f64::MIN, f64::max);
+                    let closest: Vec<_> = magnetic.filter(|&t| cosine(&spins.moment(t), m) > cmax - 1e-6).collect();
+                    // and then the closest in magnitude of the moment in μB
+                    let distance = |t: u8| (norm(&spins.moment(t)) * magmom - norm(m)).abs();
+                    let dmin = closest.iter().map(|&t| distance(t)).fold(f64::MAX, f64::min);
+                    let nearest: Vec<_> = closest.into_iter().filter(|&t| distance(t) < dmin + 1e-6).collect();
+                    if nearest.contains(&s) {
+                        s
+                    } else {
+                        nearest.first().copied().unwrap_or(s)
+                    }
+                }
+            })
+            .collect();
+        let reversed = spins.reverse(&converged);
+        self.spin_flipped =
+            collapsed || (converged != self.spin_ordering && reversed.as_ref() != Some(&self.spin_ordering));
+        self.converged_ordering = Some(converged);
+        self.magnetic_moment_vectors = Some(moments);
+
+        Ok(())
     }
 
     /// Save into default database.
     pub fn save(&self) -> Result<()> {
-        let key = binary_key(&self.spin_ordering);
+        let key = spin_key(&self.spin_ordering);
         info!("saving data with key {}", key);
         self.put_into_collection(&MAG_DB_CONNECTION, &key)?;
 
         Ok(())
     }
 
-    pub fn binary_key(&self) -> String {
-        binary_key(&self.spin_ordering)
+    pub fn spin_key(&self) -> String {
+        spin_key(&self.spin_ordering)
+    }
+
+    /// Return the screening energy, or `energy` if not screened.
+    pub fn screened_energy(&self) -> f64 {
+        self.screening_energy.unwrap_or(self.energy)
+    }
+
+    /// Load item with `key` from default database, or None if not found.
+    pub fn load(key: &str) -> Result<Option<Self>> {
+        load_from_db(key)
+    }
+}
+
+/// Load item of collection `T` with `key` from default database. Return None
+/// if not found, and propagate other database errors.
+pub(crate) fn load_from_db<T: Collection>(key: &str) -> Result<Option<T>> {
+    match T::get_from_collection(&MAG_DB_CONNECTION, key) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("get {key} from db")),
     }
 }
 
-/// Return binary encoded key of a spin-ordering.
-pub fn binary_key(so: &[bool]) -> String {
-    let ss: String = so.iter().map(|&spin_up| if spin_up { "1" } else { "0" }).collect();
+/// Return true if database error `e` is caused by a missing record.
+fn is_not_found(e: &gut::prelude::Error) -> bool {
+    e.chain().any(|x| x.to_string().to_lowercase().contains("not found"))
+}
+
+/// Return the key of a spin-ordering, with one digit for the state of each
+/// site, such as `100110011001` for up/down spins.
+pub fn spin_key(so: &[u8]) -> String {
+    let ss: String = so.iter().map(|&s| std::char::from_digit(s as u32, 36).expect("spin state")).collect();
     ss
 }
 
+/// Select values of magnetic `sites` from `values` of all atoms, checking
+/// the number of sites is `n`.
+fn select_sites<T: Copy>(values: &[T], sites: &[usize], n: usize) -> Result<Vec<T>> {
+    if sites.len() != n {
+        bail!("found {} magnetic sites for spin-ordering of {} sites", sites.len(), n);
+    }
+    sites
+        .iter()
+        .map(|&i| values.get(i).copied())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("found {} magnetic moments for magnetic sites {:?}", values.len(), sites))
+}
+
+/// Parse spin-ordering from `key` in the format of `spin_key`.
+pub fn parse_spin_key(key: &str) -> Result<Vec<u8>> {
+    key.chars()
+        .map(|c| match c.to_digit(36) {
+            Some(s) => Ok(s as u8),
+            None => bail!("bad key: {}", key),
+        })
+        .collect()
+}
+
 impl MagneticState {
+    /// Return all magnetic states in database.
+    pub fn list_all() -> Result<Vec<Self>> {
+        let items = Self::list_collection(&MAG_DB_CONNECTION)?;
+        Ok(items)
+    }
+
+    /// Show details of item `key` in database.
+    pub fn show(key: &str) -> Result<()> {
+        let ms = Self::get_from_collection(&MAG_DB_CONNECTION, key).with_context(|| format!("get {key} from db"))?;
+        println!("{}", serde_json::to_string_pretty(&ms)?);
+        Ok(())
+    }
+
     pub fn list_db() -> Result<()> {
         let mut items = Self::list_collection(&MAG_DB_CONNECTION)?;
         if items.is_empty() {
@@ -69,10 +344,17 @@ impl MagneticState {
             println!("Found {} items.", items.len());
             println!("{:^width$} => {:^12}", "key", "energy", width = items[0].spin_ordering.len());
 
-            items.sort_by(|a, b| a.energy.partial_cmp(&b.energy).unwrap_or(std::cmp::Ordering::Less));
+            items.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+            let nflipped = items.iter().filter(|ms| ms.spin_flipped).count();
             for ms in items {
-                let key = ms.binary_key();
-                println!("{} => {:<-12.4}", key, ms.energy);
+                let key = ms.spin_key();
+                match &ms.converged_ordering {
+                    Some(so) if ms.spin_flipped => println!("{} => {:<-12.4} flipped to {}", key, ms.energy, spin_key(so)),
+                    _ => println!("{} => {:<-12.4}", key, ms.energy),
+                }
+            }
+            if nflipped > 0 {
+                println!("{} items relaxed into different spin-orderings.", nflipped);
             }
         }
         Ok(())
@@ -87,4 +369,100 @@ fn test_list_db() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_parallel_evaluation() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counting evaluations of spin-orderings equivalent under reversal.
+    struct Counter(AtomicUsize);
+
+    impl EvaluateMagneticState for Counter {
+        fn canonicalize(&self, so: &[u8]) -> Vec<u8> {
+            SpinStates::default().normalize(so)
+        }
+
+        fn evaluate_new(&self, so: &[u8]) -> Result<MagneticState> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            Ok(MagneticState::new(so, -1.0))
+        }
+    }
+
+    let counter = Counter(AtomicUsize::new(0));
+    std::thread::scope(|s| {
+        for so in [[0, 1, 1, 0, 0, 1, 1], [1, 0, 0, 1, 1, 0, 0], [0, 1, 1, 0, 0, 1, 1]] {
+            let counter = &counter;
+            s.spawn(move || counter.evaluate(&so).unwrap());
+        }
+    });
+    assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_spin_flips() -> Result<()> {
+    let spins = SpinStates::default();
+    let sites = [0, 1, 2];
+    let magmoms = [5.0; 3];
+    let mut ms = MagneticState::new(&[1, 0, 1], -1.0);
+    ms.set_magnetic_moments(&[-4.0, 4.0, -4.0], &sites, &magmoms, 0.5, &spins)?;
+    assert!(!ms.spin_flipped);
+    ms.set_magnetic_moments(&[4.0, 4.0, 4.0], &sites, &magmoms, 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert_eq!(ms.converged_ordering, Some(vec![1, 1, 1]));
+    assert!(ms.set_magnetic_moments(&[4.0, 4.0], &sites, &magmoms, 0.5, &spins).is_err());
+
+    // magnetic species after nonmagnetic ones, such as O2 Fe3
+    let moments = [0.1, -0.1, 4.0, -4.0, 4.0];
+    ms.set_magnetic_moments(&moments, &[2, 3, 4], &magmoms, 0.5, &spins)?;
+    assert!(!ms.spin_flipped);
+    assert_eq!(ms.magnetic_moments, Some(vec![4.0, -4.0, 4.0]));
+    // non-contiguous sites
+    ms.set_magnetic_moments(&moments, &[2, 0, 4], &magmoms, 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert!(ms.set_magnetic_moments(&moments, &[2, 3, 5], &magmoms, 0.5, &spins).is_err());
+
+    // up, down and zero
+    let spins = SpinStates::new(vec![-1.0, 1.0, 0.0])?;
+    let mut ms = MagneticState::new(&[1, 0, 2], -1.0);
+    ms.set_magnetic_moments(&[4.0, -4.0, 0.1], &sites, &magmoms, 0.5, &spins)?;
+    assert!(!ms.spin_flipped);
+    ms.set_magnetic_moments(&[4.0, 0.1, 3.0], &sites, &magmoms, 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert_eq!(ms.converged_ordering, Some(vec![1, 2, 1]));
+
+    // high-spin and low-spin states
+    let spins = SpinStates::new(vec![1.0, 0.4, -1.0, -0.4])?;
+    let mut ms = MagneticState::new(&[0, 2, 1], -1.0);
+    ms.set_magnetic_moments(&[4.2, -4.1, 1.8], &sites, &magmoms, 0.5, &spins)?;
+    assert!(!ms.spin_flipped);
+    // high-spin relaxed into low-spin
+    ms.set_magnetic_moments(&[1.9, -4.1, 1.8], &sites, &magmoms, 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert_eq!(ms.converged_ordering, Some(vec![1, 2, 1]));
+    // low-spin is high-spin of a site with small initial MAGMOM
+    ms.set_magnetic_moments(&[4.2, -4.1, 1.8], &sites, &[5.0, 5.0, 2.0], 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert_eq!(ms.converged_ordering, Some(vec![0, 2, 0]));
+    assert!(ms.set_magnetic_moments(&[4.2, -4.1, 1.8], &sites, &[5.0], 0.5, &spins).is_err());
+
+    // non-collinear: up, down and in-plane
+    let spins: SpinStates = serde_json::from_str("[1.0, -1.0, [1.0, 0.0, 0.0], [-1.0, 0.0, 0.0]]")?;
+    let mut ms = MagneticState::new(&[0, 2, 3], -1.0);
+    ms.set_magnetic_moment_vectors(&[[0.1, 0.0, 3.9], [3.8, 0.5, 0.2], [-4.0, 0.0, 0.0]], &sites, &magmoms, 0.5, &spins)?;
+    assert!(!ms.spin_flipped);
+    ms.set_magnetic_moment_vectors(&[[0.1, 0.0, 3.9], [0.0, 0.0, -4.0], [-4.0, 0.0, 0.0]], &sites, &magmoms, 0.5, &spins)?;
+    assert!(ms.spin_flipped);
+    assert_eq!(ms.converged_ordering, Some(vec![0, 1, 3]));
+    assert_eq!(ms.magnetic_moments, None);
+
+    // spin-orderings saved as booleans in old versions
+    let ms: MagneticState = serde_json::from_str(r#"{"spin_ordering":[true,false],"energy":-1.0,"magnetic_moments":null,"timestamp":null}"#)?;
+    assert_eq!(ms.spin_ordering, vec![1, 0]);
+    assert_eq!(parse_spin_key(&ms.spin_key())?, vec![1, 0]);
+
+    Ok(())
+}
 // test:1 ends here
@@ -0,0 +1,341 @@
+// [[file:../magman.note::0c7e4b19][0c7e4b19]]
+use super::*;
+
+use spdkit::encoding::Binary;
+use spdkit::operators::selection::RouletteWheelSelection;
+use spdkit::operators::selection::StochasticUniversalSampling as SusSelection;
+use spdkit::operators::variation::TriadicCrossOver;
+use spdkit::population::{Member, Population};
+use spdkit::prelude::*;
+// 0c7e4b19 ends here
+
+// [[file:../magman.note::5e1d8a60][5e1d8a60]]
+/// Crossover operator for breeding new genomes.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Crossover {
+    /// Triadic crossover using three parents.
+    #[default]
+    Triadic,
+    /// Each bit is taken from either parent with equal probability.
+    Uniform,
+    /// Exchange tails of two parents after a random cut point.
+    OnePoint,
+    /// Exchange the segment between two random cut points.
+    TwoPoint,
+}
+
+impl Crossover {
+    /// The number of parents required for breeding.
+    fn nparents(&self) -> usize {
+        match self {
+            Self::Triadic => 3,
+            _ => 2,
+        }
+    }
+}
+
+/// Selection operator for choosing parents from population.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Selector {
+    /// Stochastic universal sampling.
+    #[default]
+    Sus,
+    /// Choose the lowest-energy one among `tournament_size` random members.
+    Tournament,
+    /// Roulette wheel selection.
+    Roulette,
+}
+
+/// Mutation operator applied to newly bred genomes.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mutation {
+    /// Flip a random spin.
+    #[default]
+    BitFlip,
+    /// Swap two random spins of opposite directions, which keeps the net
+    /// magnetization.
+    Swap,
+}
+// 5e1d8a60 ends here
+
+// [[file:../magman.note::a83f6d27][a83f6d27]]
+/// Parents selection as configured.
+#[derive(Debug, Clone)]
+pub struct MagSelection {
+    selector: Selector,
+    nparents: usize,
+    tournament_size: usize,
+}
+
+impl SelectionOperator for MagSelection {
+    fn select_from<'a, G, R>(&self, population: &'a Population<G>, rng: &mut R) -> Vec<Member<'a, G>>
+    where
+        G: Genome,
+        R: Rng + Sized,
+    {
+        match self.selector {
+            Selector::Sus => SusSelection::new(self.nparents).select_from(population, rng),
+            Selector::Roulette => RouletteWheelSelection::new(self.nparents).select_from(population, rng),
+            Selector::Tournament => {
+                let values: Vec<_> = population.members().map(|m| m.objective_value()).collect();
+                let mut selected = vec![];
+                for _ in 0..self.nparents {
+                    let winner = (0..self.tournament_size.max(1))
+                        .map(|_| rng.gen_range(0..values.len()))
+                        .min_by(|&i, &j| values[i].total_cmp(&values[j]))
+                        .unwrap();
+                    selected.push(population.members().nth(winner).unwrap());
+                }
+                selected
+            }
+        }
+    }
+}
+
+/// Crossover followed by mutation as configured.
+#[derive(Debug, Clone)]
+pub struct MagVariation {
+    crossover: Crossover,
+    mutation: Mutation,
+    mutation_rate: f64,
+    /// The allowed numbers of spin-up in genome.
+    spin_up_counts: Option<Vec<usize>>,
+    /// Spin states for repairing invalid codes in genome.
+    spins: crate::spin::SpinStates,
+}
+
+impl VariationOperator<Binary> for MagVariation {
+    fn breed_from<R: Rng + Sized>(&self, parents: &[Member<Binary>], rng: &mut R) -> Vec<Binary> {
+        let mut children = match self.crossover {
+            Crossover::Triadic => TriadicCrossOver.breed_from(parents, rng),
+            _ => {
+                let p1 = parents[0].individual.genome();
+                let p2 = parents[1].individual.genome();
+                self.crossover_pair(p1, p2, rng)
+            }
+        };
+        // keep children in the allowed magnetic sector
+        if let Some(nups) = &self.spin_up_counts {
+            for (i, child) in children.iter_mut().enumerate() {
+                if !nups.contains(&count_spin_up(child)) {
+                    let nup = count_spin_up(parents[i % parents.len()].individual.genome());
+                    repair_spin_up_count(child, nup, rng);
+                }
+            }
+        }
+        for child in children.iter_mut() {
+            if rng.gen::<f64>() < self.mutation_rate {
+                self.mutate(child, rng);
+            }
+            self.spins.repair(child, rng);
+        }
+        children
+    }
+}
+
+impl MagVariation {
+    /// Breed two children from parents `p1` and `p2`. Cut points are
+    /// aligned to sites, so that each site inherits its spin state from
+    /// either parent.
+    fn crossover_pair<R: Rng + Sized>(&self, p1: &Binary, p2: &Binary, rng: &mut R) -> Vec<Binary> {
+        let m = self.spins.bits_per_site();
+        let n = p1.len() / m;
+        let mut c1 = p1.to_vec();
+        let mut c2 = p2.to_vec();
+        let swapped: Vec<bool> = match self.crossover {
+            Crossover::Uniform => (0..n).map(|_| rng.gen()).collect(),
+            Crossover::OnePoint if n > 1 => {
+                let k = rng.gen_range(1..n);
+                (0..n).map(|i| i >= k).collect()
+            }
+            Crossover::TwoPoint if n > 2 => {
+                let i = rng.gen_range(1..n - 1);
+                let j = rng.gen_range(i + 1..n);
+                (0..n).map(|k| k >= i && k < j).collect()
+            }
+            _ => vec![false; n],
+        };
+        for (k, _) in swapped.iter().enumerate().filter(|(_, &s)| s) {
+            c1[k * m..(k + 1) * m].swap_with_slice(&mut c2[k * m..(k + 1) * m]);
+        }
+        vec![Binary::new(c1), Binary::new(c2)]
+    }
+
+    /// Mutate genome `g` in place.
+    fn mutate<R: Rng + Sized>(&self, g: &mut Binary, rng: &mut R) {
+        if g.is_empty() {
+            return;
+        }
+        match self.mutation {
+            Mutation::BitFlip => {
+                let i = rng.gen_range(0..g.len());
+                g[i] = !g[i];
+            }
+            Mutation::Swap => {
+                let m = self.spins.bits_per_site();
+                if m == 1 {
+                    let ups: Vec<_> = (0..g.len()).filter(|&i| g[i]).collect();
+                    let downs: Vec<_> = (0..g.len()).filter(|&i| !g[i]).collect();
+                    if !ups.is_empty() && !downs.is_empty() {
+                        let i = ups[rng.gen_range(0..ups.len())];
+                        let j = downs[rng.gen_range(0..downs.len())];
+                        g.swap(i, j);
+                    }
+                } else {
+                    // swap states of two sites as a whole
+                    let i = rng.gen_range(0..g.len() / m);
+                    let others: Vec<_> = (0..g.len() / m).filter(|&j| g[j * m..(j + 1) * m] != g[i * m..(i + 1) * m]).collect();
+                    if !others.is_empty() {
+                        let j = others[rng.gen_range(0..others.len())];
+                        for k in 0..m {
+                            g.swap(i * m + k, j * m + k);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Return the number of spin-up in genome `g`.
+pub fn count_spin_up(g: &[bool]) -> usize {
+    g.iter().filter(|&&b| b).count()
+}
+
+/// Flip random spins in genome `g` until it has `nup` spin-up.
+fn repair_spin_up_count<R: Rng + Sized>(g: &mut Binary, nup: usize, rng: &mut R) {
+    let nup = nup.min(g.len());
+    loop {
+        let n = count_spin_up(g);
+        if n == nup {
+            break;
+        }
+        // flip a random spin-up if too many, or a random spin-down otherwise
+        let candidates: Vec<_> = (0..g.len()).filter(|&i| g[i] == (n > nup)).collect();
+        let i = candidates[rng.gen_range(0..candidates.len())];
+        g[i] = !g[i];
+    }
+}
+
+/// Return selection and variation operators for genetic search as set in
+/// `config`, with the allowed numbers of spin-up `spin_up_counts` in genome
+/// and spin states `spins` of magnetic sites.
+pub fn genetic_operators(
+    config: &crate::config::Search,
+    spin_up_counts: Option<Vec<usize>>,
+    spins: &crate::spin::SpinStates,
+) -> (MagSelection, MagVariation) {
+    // bit-flip mutation changes net magnetization
+    let mutation = if spin_up_counts.is_some() && config.mutation != Mutation::Swap {
+        info!("swap mutation is used for constrained net magnetization.");
+        Mutation::Swap
+    } else {
+        config.mutation
+    };
+    // triadic crossover mixes bits within a site of multiple spin states
+    let crossover = if spins.bits_per_site() > 1 && config.crossover == Crossover::Triadic {
+        info!("uniform crossover is used for multiple spin states.");
+        Crossover::Uniform
+    } else {
+        config.crossover
+    };
+    let selection = MagSelection {
+        selector: config.selector,
+        nparents: crossover.nparents(),
+        tournament_size: config.tournament_size,
+    };
+    let variation = MagVariation {
+        crossover,
+        mutation,
+        mutation_rate: config.mutation_rate,
+        spin_up_counts,
+        spins: spins.clone(),
+    };
+    (selection, variation)
+}
+// a83f6d27 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_crossover_and_mutation() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let p1 = Binary::new(vec![true; 8]);
+    let p2 = Binary::new(vec![false; 8]);
+    for crossover in [Crossover::Uniform, Crossover::OnePoint, Crossover::TwoPoint] {
+        let variation = MagVariation {
+            crossover,
+            mutation: Mutation::Swap,
+            mutation_rate: 0.0,
+            spin_up_counts: None,
+            spins: Default::default(),
+        };
+        let children = variation.crossover_pair(&p1, &p2, &mut rng);
+        assert_eq!(children.len(), 2);
+        // each bit comes from one of the parents
+        assert!(children[0].iter().zip(children[1].iter()).all(|(a, b)| a != b));
+    }
+
+    // one-point crossover exchanges tails
+    let variation = MagVariation {
+        crossover: Crossover::OnePoint,
+        mutation: Mutation::BitFlip,
+        mutation_rate: 1.0,
+        spin_up_counts: None,
+        spins: Default::default(),
+    };
+    let children = variation.crossover_pair(&p1, &p2, &mut rng);
+    let k = children[0].iter().position(|&b| !b).unwrap();
+    assert!(children[0][k..].iter().all(|&b| !b));
+
+    // swap mutation keeps the number of spin-up
+    let variation = MagVariation { mutation: Mutation::Swap, ..variation };
+    let mut g = Binary::new(vec![true, true, false, false, true]);
+    variation.mutate(&mut g, &mut rng);
+    assert_eq!(g.iter().filter(|&&b| b).count(), 3);
+    let variation = MagVariation { mutation: Mutation::BitFlip, ..variation };
+    let g0 = g.clone();
+    variation.mutate(&mut g, &mut rng);
+    assert_eq!(g.iter().zip(g0.iter()).filter(|(a, b)| a != b).count(), 1);
+
+    // sites of multiple spin states are never split
+    let spins = crate::spin::SpinStates::new(vec![-1.0, 1.0, 0.0]).unwrap();
+    let p1 = Binary::new(spins.encode(&[0, 1, 2, 0, 1, 2]));
+    let p2 = Binary::new(spins.encode(&[2, 2, 1, 1, 0, 0]));
+    for crossover in [Crossover::Uniform, Crossover::OnePoint, Crossover::TwoPoint] {
+        let variation = MagVariation {
+            crossover,
+            mutation: Mutation::Swap,
+            mutation_rate: 0.0,
+            spin_up_counts: None,
+            spins: spins.clone(),
+        };
+        for _ in 0..10 {
+            for child in variation.crossover_pair(&p1, &p2, &mut rng) {
+                let so = spins.decode(&child);
+                assert!(spins.check_spin_ordering(&so).is_ok());
+            }
+        }
+    }
+    let variation = MagVariation { mutation: Mutation::Swap, spins: spins.clone(), ..variation };
+    let mut g = p1.clone();
+    variation.mutate(&mut g, &mut rng);
+    let mut so = spins.decode(&g);
+    assert!(spins.check_spin_ordering(&so).is_ok());
+    so.sort();
+    assert_eq!(so, vec![0, 0, 1, 1, 2, 2]);
+    let (_, variation) = genetic_operators(&crate::config::Config::default().search, None, &spins);
+    assert_eq!(variation.crossover, Crossover::Uniform);
+
+    // repair into the allowed magnetic sector
+    let mut g = Binary::new(vec![true; 6]);
+    repair_spin_up_count(&mut g, 2, &mut rng);
+    assert_eq!(count_spin_up(&g), 2);
+    repair_spin_up_count(&mut g, 5, &mut rng);
+    assert_eq!(count_spin_up(&g), 5);
+}
+// test:1 ends here
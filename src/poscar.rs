@@ -0,0 +1,248 @@
+// [[file:../magman.note::0b6f1c2e][0b6f1c2e]]
+use super::*;
+use std::path::Path;
+// 0b6f1c2e ends here
+
+// [[file:../magman.note::8d2e4a90][8d2e4a90]]
+/// Crystal structure read from VASP POSCAR file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Poscar {
+    /// Lattice vectors in rows, in Angstrom.
+    pub lattice: [[f64; 3]; 3],
+    /// Element symbol of each atom in POSCAR order.
+    pub symbols: Vec<String>,
+    /// Fractional coordinates of each atom in POSCAR order.
+    pub positions: Vec<[f64; 3]>,
+}
+
+impl Poscar {
+    /// Read crystal structure from POSCAR file in `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = gut::fs::read_file(path).with_context(|| format!("read POSCAR {}", path.display()))?;
+        Self::parse(&s).with_context(|| format!("parse POSCAR {}", path.display()))
+    }
+
+    /// Parse crystal structure from POSCAR content in `s`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        let mut next_line = || lines.next().ok_or_else(|| anyhow!("unexpected end of POSCAR"));
+
+        // the title line
+        let _ = next_line()?;
+        let scale: f64 = next_line()?
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow!("missing scaling factor"))?
+            .parse()?;
+        let mut lattice = [[0.0; 3]; 3];
+        for v in lattice.iter_mut() {
+            *v = parse_vector(next_line()?)?;
+        }
+        // negative scaling factor means the total volume of the cell
+        let scale = if scale < 0.0 { (-scale / volume(&lattice)).cbrt() } else { scale };
+        for v in lattice.iter_mut() {
+            v.iter_mut().for_each(|x| *x *= scale);
+        }
+
+        // element symbols are optional in VASP 4 format
+        let mut line = next_line()?;
+        let species: Option<Vec<String>> = if line.split_whitespace().all(|x| x.parse::<usize>().is_err()) {
+            let species = line.split_whitespace().map(|x| x.to_string()).collect();
+            line = next_line()?;
+            Some(species)
+        } else {
+            None
+        };
+        let counts: Vec<usize> = line.split_whitespace().map(|x| x.parse()).collect::<std::result::Result<_, _>>()?;
+        let species = species.unwrap_or_else(|| (1..=counts.len()).map(|i| format!("X{i}")).collect());
+        if species.len() != counts.len() {
+            bail!("species {:?} and atom counts {:?} mismatch", species, counts);
+        }
+        let symbols: Vec<String> = species
+            .iter()
+            .zip(counts.iter())
+            .flat_map(|(s, &n)| std::iter::repeat(s.clone()).take(n))
+            .collect();
+
+        let mut line = next_line()?.trim_start();
+        if line.starts_with(['S', 's']) {
+            line = next_line()?.trim_start();
+        }
+        let cartesian = line.starts_with(['C', 'c', 'K', 'k']);
+        let mut positions = vec![];
+        for _ in 0..symbols.len() {
+            let p = parse_vector(next_line()?)?;
+            let p = if cartesian {
+                let p = [p[0] * scale, p[1] * scale, p[2] * scale];
+                to_fractional(&lattice, p)
+            } else {
+                p
+            };
+            positions.push(p);
+        }
+
+        Ok(Self {
+            lattice,
+            symbols,
+            positions,
+        })
+    }
+
+    /// Return the number of atoms.
+    pub fn natoms(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Return cartesian coordinates of fractional coordinates `p`.
+    pub fn to_cartesian(&self, p: [f64; 3]) -> [f64; 3] {
+        let a = &self.lattice;
+        let mut x = [0.0; 3];
+        for i in 0..3 {
+            x[i] = p[0] * a[0][i] + p[1] * a[1][i] + p[2] * a[2][i];
+        }
+        x
+    }
+
+    /// Return the distance between fractional coordinates `p1` and `p2`
+    /// under minimum image convention.
+    pub fn distance(&self, p1: [f64; 3], p2: [f64; 3]) -> f64 {
+        let mut d = [0.0; 3];
+        for i in 0..3 {
+            let x = p2[i] - p1[i];
+            d[i] = x - x.round();
+        }
+        norm(self.to_cartesian(d))
+    }
+}
+
+/// Magnetic sites in POSCAR, selected by element symbols such as `["Fe"]`,
+/// or by atom indices counting from 1 such as `[1, 2, 5]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SiteSelection {
+    Indices(Vec<usize>),
+    Species(Vec<String>),
+}
+
+/// Magnetic sites selected from atoms in POSCAR.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagneticSites {
+    /// The total number of atoms.
+    pub natoms: usize,
+    /// Atom indices of magnetic sites in POSCAR order, counting from 0.
+    pub sites: Vec<usize>,
+    /// Element symbol of each magnetic site.
+    pub symbols: Vec<String>,
+}
+
+impl Poscar {
+    /// Return magnetic sites in `selection`.
+    pub fn select_sites(&self, selection: &SiteSelection) -> Result<MagneticSites> {
+        let sites: Vec<_> = match selection {
+            SiteSelection::Indices(indices) => {
+                if let Some(&i) = indices.iter().find(|&&i| i == 0 || i > self.natoms()) {
+                    bail!("magnetic site {} out of range of {} atoms", i, self.natoms());
+                }
+                let mut sites: Vec<_> = indices.iter().map(|i| i - 1).collect();
+                sites.sort_unstable();
+                sites.dedup();
+                sites
+            }
+            SiteSelection::Species(species) => {
+                if let Some(x) = species.iter().find(|x| !self.symbols.contains(x)) {
+                    bail!("magnetic species {} not found in POSCAR", x);
+                }
+                (0..self.natoms()).filter(|&i| species.contains(&self.symbols[i])).collect()
+            }
+        };
+        if sites.is_empty() {
+            bail!("no magnetic sites selected");
+        }
+
+        Ok(MagneticSites {
+            natoms: self.natoms(),
+            symbols: sites.iter().map(|&i| self.symbols[i].clone()).collect(),
+            sites,
+        })
+    }
+
+    /// Return the first `n` atoms as magnetic sites.
+    pub fn leading_sites(&self, n: usize) -> Result<MagneticSites> {
+        if n > self.natoms() {
+            bail!("{} magnetic sites out of range of {} atoms", n, self.natoms());
+        }
+        Ok(MagneticSites {
+            natoms: self.natoms(),
+            sites: (0..n).collect(),
+            symbols: self.symbols[..n].to_vec(),
+        })
+    }
+}
+
+fn parse_vector(line: &str) -> Result<[f64; 3]> {
+    let parts: Vec<f64> = line
+        .split_whitespace()
+        .take(3)
+        .map(|x| x.parse())
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("invalid vector line: {line:?}"))?;
+    if parts.len() != 3 {
+        bail!("invalid vector line: {line:?}");
+    }
+    Ok([parts[0], parts[1], parts[2]])
+}
+
+fn norm(v: [f64; 3]) -> f64 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn volume(a: &[[f64; 3]; 3]) -> f64 {
+    let (x, y, z) = (a[0], a[1], a[2]);
+    (x[0] * (y[1] * z[2] - y[2] * z[1]) - x[1] * (y[0] * z[2] - y[2] * z[0]) + x[2] * (y[0] * z[1] - y[1] * z[0])).abs()
+}
+
+// solve x = f * A for fractional coordinates f using Cramer's rule
+fn to_fractional(a: &[[f64; 3]; 3], x: [f64; 3]) -> [f64; 3] {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(*a);
+    let mut f = [0.0; 3];
+    for (k, fk) in f.iter_mut().enumerate() {
+        let mut m = *a;
+        m[k] = x;
+        *fk = det(m) / d;
+    }
+    f
+}
+// 8d2e4a90 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_read_poscar() -> Result<()> {
+    let poscar = Poscar::from_file("tests/files/template/POSCAR")?;
+    assert_eq!(poscar.natoms(), 30);
+    assert_eq!(poscar.symbols.iter().filter(|s| *s == "Fe").count(), 12);
+    assert_eq!(poscar.symbols[12], "O");
+    assert!((poscar.lattice[2][2] - 13.694325366675399).abs() < 1e-8);
+
+    // Fe-Fe distance along c axis
+    let d = poscar.distance(poscar.positions[1], poscar.positions[4]);
+    assert!((d - 0.21 * 13.694325366675399).abs() < 1e-6);
+
+    // magnetic sites by species or by indices
+    let selection: SiteSelection = serde_json::from_str(r#"["Fe"]"#)?;
+    let sites = poscar.select_sites(&selection)?;
+    assert_eq!(sites.natoms, 30);
+    assert_eq!(sites.sites, (0..12).collect::<Vec<_>>());
+    let selection: SiteSelection = serde_json::from_str("[13, 1, 2]")?;
+    assert_eq!(poscar.select_sites(&selection)?.sites, vec![0, 1, 12]);
+    assert!(poscar.select_sites(&SiteSelection::Indices(vec![31])).is_err());
+    assert!(poscar.select_sites(&SiteSelection::Species(vec!["Cr".into()])).is_err());
+    assert_eq!(poscar.leading_sites(12)?, sites);
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,226 @@
+// [[file:../magman.note::2d9b6e17][2d9b6e17]]
+use super::*;
+use crate::failure::{FailureKind, JobFailure};
+use crate::incar::Incar;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+// 2d9b6e17 ends here
+
+// [[file:../magman.note::f05c3a8e][f05c3a8e]]
+/// The file recording recovery attempts in each job directory.
+const ATTEMPTS_FILE: &str = "magman-attempts.json";
+
+/// Recovery of failed VASP jobs by rewriting INCAR in job directory and
+/// rerunning.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recovery {
+    /// The max number of reruns for each job.
+    #[serde(default = "default_max_attempts")]
+    max_attempts: usize,
+
+    /// INCAR fixes for failed jobs. On each rerun, the first fix matching
+    /// the failure and not applied yet is applied on top of previous ones.
+    #[serde(default = "default_fixes")]
+    fixes: Vec<Fix>,
+}
+
+/// INCAR changes for a class of failures.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fix {
+    /// The failure class: "not-converged", "crashed" or "unfinished".
+    status: FailureKind,
+    /// Apply only if the failure reason contains this text, such as
+    /// "ZBRENT".
+    #[serde(default)]
+    pattern: Option<String>,
+    /// INCAR tags to be set. No change means rerunning as it is.
+    #[serde(default)]
+    incar: BTreeMap<String, String>,
+}
+
+/// A recovery attempt of failed job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attempt {
+    pub status: FailureKind,
+    pub reason: String,
+    /// INCAR tags changed for rerunning.
+    pub changes: BTreeMap<String, String>,
+}
+
+fn default_max_attempts() -> usize {
+    3
+}
+
+fn default_fixes() -> Vec<Fix> {
+    let fix = |status, pattern: Option<&str>, incar: &[(&str, &str)]| Fix {
+        status,
+        pattern: pattern.map(|x| x.to_string()),
+        incar: incar.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+    };
+    vec![
+        fix(FailureKind::NotConverged, None, &[("ALGO", "Normal"), ("NELM", "150")]),
+        fix(FailureKind::NotConverged, None, &[("ALGO", "All"), ("AMIX", "0.1"), ("BMIX", "0.01")]),
+        fix(FailureKind::Crashed, Some("ZBRENT"), &[("IBRION", "1")]),
+        fix(FailureKind::Crashed, Some("BRMIX"), &[("ISYM", "0")]),
+        fix(FailureKind::Crashed, Some("EDDDAV"), &[("ALGO", "All")]),
+        fix(FailureKind::Crashed, Some("ZPOTRF"), &[("ISYM", "0"), ("POTIM", "0.1")]),
+        fix(FailureKind::Crashed, None, &[]),
+    ]
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            fixes: default_fixes(),
+        }
+    }
+}
+
+impl Fix {
+    fn matches(&self, failure: &JobFailure) -> bool {
+        self.status == failure.status && self.pattern.as_ref().map_or(true, |p| failure.reason.contains(p.as_str()))
+    }
+}
+
+impl Recovery {
+    /// Return INCAR changes for rerunning job failed with `failure`, given
+    /// previous `attempts`: the first matching fix not applied yet. Return
+    /// None if giving up.
+    pub fn next_fix(&self, failure: &JobFailure, attempts: &[Attempt]) -> Option<&BTreeMap<String, String>> {
+        if attempts.len() >= self.max_attempts {
+            return None;
+        }
+        self.fixes
+            .iter()
+            .filter(|fix| fix.matches(failure))
+            .find(|fix| attempts.iter().all(|a| a.changes != fix.incar))
+            .map(|fix| &fix.incar)
+    }
+
+    /// Apply the next fix for `failure` to INCAR in job directory `adir`,
+    /// and record the attempt. Return false if giving up.
+    pub fn apply(&self, adir: &Path, failure: &JobFailure, attempts: &mut Vec<Attempt>) -> Result<bool> {
+        let changes = match self.next_fix(failure, attempts) {
+            Some(changes) => changes.clone(),
+            None => return Ok(false),
+        };
+        let path = adir.join("INCAR");
+        let mut incar = Incar::from_file(&path)?;
+        for (tag, value) in changes.iter() {
+            incar.set(tag, value);
+        }
+        // IALGO takes precedence over ALGO in VASP
+        if changes.keys().any(|tag| tag.eq_ignore_ascii_case("ALGO")) && incar.remove("IALGO") {
+            info!("IALGO is removed from {} in favor of ALGO", path.display());
+        }
+        gut::fs::write_to_file(&path, &incar.to_string()).with_context(|| format!("write {}", path.display()))?;
+        info!("rerun job {} with {:?} for {}", adir.display(), changes, failure);
+
+        attempts.push(Attempt {
+            status: failure.status,
+            reason: failure.reason.clone(),
+            changes,
+        });
+        write_attempts(adir, attempts)?;
+
+        Ok(true)
+    }
+}
+
+/// Read recovery attempts recorded in job directory `adir`.
+pub fn read_attempts(adir: &Path) -> Vec<Attempt> {
+    gut::fs::read_file(adir.join(ATTEMPTS_FILE))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Remove recovery attempts recorded in job directory `adir`, such as when
+/// its input files are prepared again.
+pub fn reset_attempts(adir: &Path) -> Result<()> {
+    let path = adir.join(ATTEMPTS_FILE);
+    if path.exists() {
+        std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn write_attempts(adir: &Path, attempts: &[Attempt]) -> Result<()> {
+    let path = adir.join(ATTEMPTS_FILE);
+    gut::fs::write_to_file(&path, &serde_json::to_string_pretty(attempts)?).with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+// f05c3a8e ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_recovery() -> Result<()> {
+    let recovery = Recovery::default();
+    let failure = JobFailure {
+        status: FailureKind::Crashed,
+        reason: "ZBRENT: fatal error in bracketing".into(),
+        attempts: vec![],
+    };
+
+    let dir = tempfile::tempdir()?;
+    let adir = dir.path();
+    std::fs::copy("tests/files/template/INCAR", adir.join("INCAR"))?;
+    let mut attempts = read_attempts(adir);
+    assert!(attempts.is_empty());
+    // ZBRENT fix, then rerun once as a crash
+    assert!(recovery.apply(adir, &failure, &mut attempts)?);
+    let incar = Incar::from_file(adir.join("INCAR"))?;
+    assert_eq!(incar.get("IBRION"), Some("1"));
+    assert_eq!(incar.get("ENCUT"), Some("500"));
+    assert_eq!(incar.get("IALGO"), Some("48"));
+    assert!(recovery.apply(adir, &failure, &mut attempts)?);
+    assert!(attempts[1].changes.is_empty());
+    assert!(!recovery.apply(adir, &failure, &mut attempts)?);
+    assert_eq!(read_attempts(adir), attempts);
+
+    // give up after max attempts
+    let failure = JobFailure {
+        status: FailureKind::NotConverged,
+        reason: "ionic step 1 reached NELM = 60".into(),
+        attempts: vec![],
+    };
+    assert_eq!(recovery.next_fix(&failure, &attempts[..1]).unwrap()["NELM"], "150");
+    assert_eq!(recovery.next_fix(&failure, &attempts).unwrap()["ALGO"], "Normal");
+    assert!(recovery.apply(adir, &failure, &mut attempts)?);
+    assert!(recovery.next_fix(&failure, &attempts).is_none());
+    // ALGO takes effect without IALGO in template
+    let incar = Incar::from_file(adir.join("INCAR"))?;
+    assert_eq!(incar.get("ALGO"), Some("Normal"));
+    assert_eq!(incar.get("IALGO"), None);
+
+    reset_attempts(adir)?;
+    assert!(read_attempts(adir).is_empty());
+    reset_attempts(adir)?;
+
+    // fixes for different crash reasons do not skip each other
+    let zbrent = JobFailure {
+        status: FailureKind::Crashed,
+        reason: "ZBRENT: fatal error in bracketing".into(),
+        attempts: vec![],
+    };
+    let brmix = JobFailure {
+        status: FailureKind::Crashed,
+        reason: "BRMIX: very serious problems".into(),
+        attempts: vec![],
+    };
+    let mut attempts = vec![];
+    assert!(recovery.apply(adir, &zbrent, &mut attempts)?);
+    assert_eq!(recovery.next_fix(&brmix, &attempts).unwrap()["ISYM"], "0");
+    assert!(recovery.apply(adir, &brmix, &mut attempts)?);
+    assert!(recovery.next_fix(&zbrent, &attempts).unwrap().is_empty());
+    assert!(recovery.next_fix(&brmix, &attempts).unwrap().is_empty());
+
+    let recovery: Recovery = serde_json::from_str(r#"{"fixes": [{"status": "unfinished"}]}"#)?;
+    assert_eq!(recovery.max_attempts, 3);
+    assert_eq!(recovery.fixes[0].status, FailureKind::Unfinished);
+
+    Ok(())
+}
+// test:1 ends here
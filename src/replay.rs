@@ -0,0 +1,185 @@
+// [[file:../magman.note::9b0f2d6c][9b0f2d6c]]
+use super::*;
+use crate::magmom::{spin_key, MagneticState};
+use crate::spin::SpinStates;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+// 9b0f2d6c ends here
+
+// [[file:../magman.note::e27a4c15][e27a4c15]]
+/// Replay evaluations from a table of precomputed energies instead of
+/// running VASP, which is useful for tuning search parameters against a
+/// complete dataset.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Replay {
+    /// The csv or json table of precomputed energies, such as the file
+    /// written by `magman export`.
+    pub table: PathBuf,
+
+    /// The penalty energy for spin-orderings missing in the table. An
+    /// error will be raised for missing items if it is not set.
+    pub missing_energy: Option<f64>,
+}
+
+lazy_static! {
+    /// The replay table set in config.
+    static ref REPLAY_TABLE: ReplayTable = {
+        let replay = crate::config::MAGMAN_CONFIG.replay.as_ref().expect("no replay table in config");
+        ReplayTable::from_config(replay).expect("Failed to read replay table!")
+    };
+}
+
+impl Replay {
+    /// Return the replay table, which will be read only once.
+    pub fn table(&self) -> &'static ReplayTable {
+        &REPLAY_TABLE
+    }
+}
+
+/// Energies of spin-orderings keyed by `spin_key`.
+#[derive(Debug, Clone)]
+pub struct ReplayTable {
+    energies: HashMap<String, f64>,
+    missing_energy: Option<f64>,
+    spins: SpinStates,
+    /// Evaluated items, which are cached in memory instead of database.
+    evaluated: Arc<Mutex<HashMap<String, MagneticState>>>,
+}
+
+impl ReplayTable {
+    /// Read table of spin-orderings in `spins` from file `path` in csv or
+    /// json format (guessed from file extension).
+    pub fn from_file<P: AsRef<Path>>(path: P, missing_energy: Option<f64>, spins: &SpinStates) -> Result<Self> {
+        let path = path.as_ref();
+        let records = match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => {
+                let txt = gut::fs::read_file(path)?;
+                serde_json::from_str(&txt).with_context(|| format!("parse json file {}", path.display()))?
+            }
+            _ => crate::table::read_csv(path)?,
+        };
+
+        let mut energies = HashMap::new();
+        for r in records {
+            if let Some(e) = energies.insert(r.directory.clone(), r.energy) {
+                warn!("duplicated item {} in {}: {} vs {}", r.directory, path.display(), e, r.energy);
+            }
+        }
+        info!("Read {} items from replay table {}", energies.len(), path.display());
+
+        Ok(Self {
+            energies,
+            missing_energy,
+            spins: spins.clone(),
+            evaluated: Default::default(),
+        })
+    }
+
+    fn from_config(replay: &Replay) -> Result<Self> {
+        let spins = crate::config::MAGMAN_CONFIG.vasp.spin_states();
+        Self::from_file(&replay.table, replay.missing_energy, spins)
+    }
+
+    /// Return the number of items in table.
+    pub fn len(&self) -> usize {
+        self.energies.len()
+    }
+
+    /// Look up energy of spin-ordering `so`. The spin-ordering with all
+    /// spins reversed is also checked, as the first spin is usually fixed in
+    /// the table.
+    pub fn energy(&self, so: &[u8]) -> Option<f64> {
+        let reversed = self.spins.reverse(so).unwrap_or_default();
+        self.energies
+            .get(&spin_key(so))
+            .or_else(|| self.energies.get(&spin_key(&reversed)))
+            .copied()
+    }
+
+    /// Return the highest energy in table.
+    pub fn highest_energy(&self) -> Option<f64> {
+        self.energies.values().copied().reduce(f64::max)
+    }
+
+    /// Return the lowest energy item in table.
+    pub fn ground_state(&self) -> Option<(&str, f64)> {
+        self.energies
+            .iter()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(k, &e)| (k.as_str(), e))
+    }
+}
+
+impl crate::magmom::EvaluateMagneticState for ReplayTable {
+    /// Spin-orderings with all spins reversed are equivalent, consistent
+    /// with the lookup in table.
+    fn canonicalize(&self, so: &[u8]) -> Vec<u8> {
+        self.spins.normalize(so)
+    }
+
+    /// Evaluate with caching in memory. Replayed items are never saved in
+    /// database.
+    fn evaluate(&self, so: &[u8]) -> Result<MagneticState> {
+        let so = &self.canonicalize(so);
+        let key = spin_key(so);
+        if let Some(ms) = self.evaluated.lock().unwrap().get(&key) {
+            return Ok(ms.clone());
+        }
+        let ms = self.evaluate_new(so)?;
+        self.evaluated.lock().unwrap().insert(key, ms.clone());
+        Ok(ms)
+    }
+
+    fn list_evaluated(&self) -> Result<Vec<MagneticState>> {
+        Ok(self.evaluated.lock().unwrap().values().cloned().collect())
+    }
+
+    fn evaluate_new(&self, so: &[u8]) -> Result<MagneticState> {
+        let key = spin_key(so);
+        let energy = match (self.energy(so), self.missing_energy) {
+            (Some(energy), _) => energy,
+            (None, Some(energy)) => {
+                warn!("item {} not found in replay table, using penalty energy {}", key, energy);
+                energy
+            }
+            (None, None) => bail!("item {} not found in replay table", key),
+        };
+        info!("item {:} energy = {:-18.6}", key, energy);
+
+        Ok(MagneticState::new(so, energy))
+    }
+}
+// e27a4c15 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_replay_table() -> Result<()> {
+    use crate::magmom::EvaluateMagneticState;
+
+    let table = ReplayTable::from_file("tests/files/results.csv", None, &SpinStates::default())?;
+    assert_eq!(table.len(), 2048);
+    let so = crate::magmom::parse_spin_key("100110011001")?;
+    let ms = table.evaluate_new(&so)?;
+    assert_eq!(ms.energy, -205.30249);
+    // equivalent spin-ordering with all spins reversed
+    let so_rev: Vec<_> = so.iter().map(|&s| 1 - s).collect();
+    assert_eq!(table.energy(&so_rev), Some(-205.30249));
+    assert!(table.evaluate_new(&so[1..]).is_err());
+
+    let table = ReplayTable { missing_energy: Some(0.0), ..table };
+    assert_eq!(table.evaluate_new(&so[1..])?.energy, 0.0);
+    assert!(table.ground_state().is_some());
+    assert!(table.highest_energy() > Some(table.ground_state().unwrap().1));
+
+    // evaluated items are cached in memory, not in database
+    assert!(table.list_evaluated()?.is_empty());
+    assert_eq!(table.evaluate(&so_rev)?.energy, -205.30249);
+    assert_eq!(table.evaluate(&so)?.spin_ordering, so);
+    assert_eq!(table.list_evaluated()?.len(), 1);
+    assert!(crate::magmom::MagneticState::load("100110011001")?.is_none());
+
+    Ok(())
+}
+// test:1 ends here
@@ -0,0 +1,231 @@
+// [[file:../magman.note::4f0e9a7d][4f0e9a7d]]
+use super::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+// 4f0e9a7d ends here
+
+// [[file:../magman.note::d7a3c1e8][d7a3c1e8]]
+/// The name of job script created in each job directory.
+const JOB_SCRIPT: &str = "magman-job.sh";
+
+/// Batch job scheduler (Slurm, PBS, ...) for running VASP jobs.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Scheduler {
+    /// Template of job submission script. The `{{cmdline}}`, `{{job_dir}}`
+    /// and `{{job_name}}` placeholders will be replaced for each job.
+    script_template: PathBuf,
+
+    /// Command for submitting job script, such as `sbatch` or `qsub`.
+    submit_cmd: String,
+
+    /// Command for querying job state, such as `squeue -h -j` or `qstat`.
+    /// The job id will be appended. The job is considered as finished when
+    /// its output no longer contains the job id, or when it fails with a
+    /// message in `unknown_job_patterns`.
+    status_cmd: String,
+
+    /// Time interval in seconds between job state queries.
+    poll_interval: f64,
+
+    /// The max number of retries when `status_cmd` fails for other
+    /// reasons, such as a scheduler timeout. The interval between retries
+    /// starts from `poll_interval` and doubles each time.
+    #[serde(default = "default_max_query_retries")]
+    max_query_retries: usize,
+
+    /// Messages of `status_cmd` for jobs unknown to the scheduler, such as
+    /// finished jobs purged from the queue.
+    #[serde(default = "default_unknown_job_patterns")]
+    unknown_job_patterns: Vec<String>,
+}
+
+fn default_max_query_retries() -> usize {
+    5
+}
+
+fn default_unknown_job_patterns() -> Vec<String> {
+    // Slurm and PBS/Torque
+    vec!["Invalid job id".into(), "Unknown Job Id".into()]
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            script_template: "template/job.sh".into(),
+            submit_cmd: "sbatch".into(),
+            status_cmd: "squeue -h -j".into(),
+            poll_interval: 30.0,
+            max_query_retries: default_max_query_retries(),
+            unknown_job_patterns: default_unknown_job_patterns(),
+        }
+    }
+}
+
+impl Scheduler {
+    /// Template of job submission script.
+    pub(crate) fn script_template(&self) -> &Path {
+        &self.script_template
+    }
+
+    /// Submit a job running `cmdline` in job directory `adir`, and wait
+    /// until it finished.
+    pub fn run(&self, cmdline: &Path, adir: &Path) -> Result<()> {
+        let script = self.write_job_script(cmdline, adir)?;
+        let job_id = self.submit(&script, adir)?;
+        info!("job {} submitted for {}", job_id, adir.display());
+        while self.is_running(&job_id, adir)? {
+            gut::utils::sleep(self.poll_interval);
+        }
+        info!("job {} finished.", job_id);
+
+        Ok(())
+    }
+
+    /// Create job script in `adir` from template.
+    fn write_job_script(&self, cmdline: &Path, adir: &Path) -> Result<PathBuf> {
+        let template = gut::fs::read_file(&self.script_template)
+            .with_context(|| format!("Failed to read job script template: {}", self.script_template.display()))?;
+        let adir = adir.canonicalize()?;
+        let job_name = adir.file_name().and_then(|x| x.to_str()).unwrap_or("magman");
+        let txt = template
+            .replace("{{cmdline}}", &cmdline.display().to_string())
+            .replace("{{job_dir}}", &adir.display().to_string())
+            .replace("{{job_name}}", job_name);
+
+        let script = adir.join(JOB_SCRIPT);
+        gut::fs::write_to_file(&script, &txt).with_context(|| format!("Failed to write job script: {}", script.display()))?;
+        // make it executable
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
+        Ok(script)
+    }
+
+    /// Submit job `script` and return the job id.
+    fn submit(&self, script: &Path, adir: &Path) -> Result<String> {
+        let (program, args) = split_cmdline(&self.submit_cmd)?;
+        let o = Command::new(program)
+            .args(args)
+            .arg(script)
+            .current_dir(adir)
+            .output()
+            .with_context(|| format!("run {:?}", self.submit_cmd))?;
+        if !o.status.success() {
+            bail!("job submission failed with output: {o:?}");
+        }
+
+        // `sbatch` prints "Submitted batch job 12345", `qsub` prints "12345.server"
+        let stdout = String::from_utf8_lossy(&o.stdout);
+        match stdout.split_whitespace().last() {
+            Some(job_id) => Ok(job_id.to_string()),
+            None => bail!("no job id found in submission output: {o:?}"),
+        }
+    }
+
+    /// Query if the job `job_id` is still queuing or running. Failed
+    /// queries are retried with backoff.
+    fn is_running(&self, job_id: &str, adir: &Path) -> Result<bool> {
+        let mut interval = self.poll_interval;
+        let mut nretries = 0;
+        loop {
+            match self.query(job_id, adir)? {
+                Ok(running) => return Ok(running),
+                Err(msg) if nretries < self.max_query_retries => {
+                    warn!("query state of job {} failed, retry in {} seconds: {}", job_id, interval, msg);
+                    gut::utils::sleep(interval);
+                    interval *= 2.0;
+                    nretries += 1;
+                }
+                Err(msg) => bail!("query state of job {} failed after {} retries: {}", job_id, nretries, msg),
+            }
+        }
+    }
+
+    /// Query job state once. Return the error message if `status_cmd`
+    /// failed for reasons other than unknown job id.
+    fn query(&self, job_id: &str, adir: &Path) -> Result<std::result::Result<bool, String>> {
+        let (program, args) = split_cmdline(&self.status_cmd)?;
+        let o = Command::new(program)
+            .args(args)
+            .arg(job_id)
+            .current_dir(adir)
+            .output()
+            .with_context(|| format!("run {:?}", self.status_cmd))?;
+        let stdout = String::from_utf8_lossy(&o.stdout);
+        trace!("job {} status: {}", job_id, stdout);
+        if o.status.success() {
+            return Ok(Ok(stdout.contains(job_id)));
+        }
+
+        let msg = format!("{}{}", stdout, String::from_utf8_lossy(&o.stderr));
+        if self.unknown_job_patterns.iter().any(|p| msg.contains(p.as_str())) {
+            Ok(Ok(false))
+        } else {
+            Ok(Err(msg.trim().to_string()))
+        }
+    }
+}
+
+fn split_cmdline(cmdline: &str) -> Result<(&str, Vec<&str>)> {
+    let mut parts = cmdline.split_whitespace();
+    match parts.next() {
+        Some(program) => Ok((program, parts.collect())),
+        None => bail!("empty command line"),
+    }
+}
+// d7a3c1e8 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_scheduler_fake_slurm() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    let write_script = |name: &str, txt: &str| -> Result<PathBuf> {
+        let path = dir.path().join(name);
+        gut::fs::write_to_file(&path, txt)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(path)
+    };
+
+    // the job is queuing until the job script finished
+    let sbatch = write_script("sbatch", "#!/bin/sh\nnohup sh \"$1\" > /dev/null 2>&1 &\necho \"Submitted batch job 42\"\n")?;
+    // the first query fails transiently, and the finished job is purged
+    let squeue = write_script(
+        "squeue",
+        "#!/bin/sh\n[ -f queried ] || { touch queried; echo 'Socket timed out' >&2; exit 1; }\n[ -f done ] && { echo 'Invalid job id specified' >&2; exit 1; }\necho \"  42 debug R\"\n",
+    )?;
+    let vasp = write_script("vasp", "#!/bin/sh\nsleep 0.5\necho \"E0= -1.0\" > OSZICAR\n")?;
+    let template = write_script("job.sh", "#!/bin/sh\n#SBATCH -J {{job_name}}\ncd {{job_dir}}\n{{cmdline}}\ntouch done\n")?;
+
+    let scheduler = Scheduler {
+        script_template: template,
+        submit_cmd: sbatch.display().to_string(),
+        status_cmd: format!("{} -h -j", squeue.display()),
+        poll_interval: 0.1,
+        ..Default::default()
+    };
+    let adir = dir.path().join("jobs").join("1010");
+    std::fs::create_dir_all(&adir)?;
+    scheduler.run(&vasp, &adir)?;
+
+    assert!(adir.join("queried").exists());
+    assert!(adir.join("done").exists());
+    assert!(adir.join("OSZICAR").exists());
+    let script = gut::fs::read_file(adir.join(JOB_SCRIPT))?;
+    assert!(script.contains("#SBATCH -J 1010"));
+
+    // give up if the scheduler keeps failing
+    let squeue = write_script("squeue-down", "#!/bin/sh\necho 'Socket timed out' >&2\nexit 1\n")?;
+    let scheduler = Scheduler {
+        status_cmd: squeue.display().to_string(),
+        max_query_retries: 2,
+        ..scheduler
+    };
+    let adir = dir.path().join("jobs").join("0110");
+    std::fs::create_dir_all(&adir)?;
+    assert!(scheduler.run(&vasp, &adir).is_err());
+
+    Ok(())
+}
+// test:1 ends here
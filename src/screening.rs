@@ -0,0 +1,101 @@
+// [[file:../magman.note::5a1e7c39][5a1e7c39]]
+use super::*;
+use std::path::{Path, PathBuf};
+// 5a1e7c39 ends here
+
+// [[file:../magman.note::c86f0b2d][c86f0b2d]]
+/// Two-stage evaluation of spin-orderings. Each spin-ordering is first
+/// calculated using a cheap screening template, and only the promising
+/// ones are recalculated using the production template.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Screening {
+    /// VASP template directory for screening calculations, such as with
+    /// low ENCUT, Gamma-only KPOINTS and loose EDIFF.
+    template_directory: PathBuf,
+
+    /// Working directory for screening calculations.
+    #[serde(default = "default_working_directory")]
+    working_directory: PathBuf,
+
+    /// The number of lowest screened spin-orderings to be refined in each
+    /// generation.
+    #[serde(default = "default_nrefine")]
+    nrefine: usize,
+
+    /// Also refine spin-orderings with screening energy within this window
+    /// above the lowest one.
+    #[serde(default)]
+    energy_window: Option<f64>,
+}
+
+fn default_working_directory() -> PathBuf {
+    "jobs-screen".into()
+}
+
+fn default_nrefine() -> usize {
+    2
+}
+
+impl Screening {
+    /// VASP template directory for screening calculations.
+    pub(crate) fn template_directory(&self) -> &Path {
+        &self.template_directory
+    }
+
+    /// Working directory for screening calculations.
+    pub(crate) fn working_directory(&self) -> &Path {
+        &self.working_directory
+    }
+
+    /// Select keys to be refined from `screened` pairs of spin-ordering key
+    /// and screening energy: the lowest `nrefine` ones, and those within
+    /// `energy_window` above the lowest. The keys are sorted by screening
+    /// energy, and duplicated keys are taken only once with the lowest
+    /// energy.
+    pub fn select(&self, screened: &[(String, f64)]) -> Vec<String> {
+        let mut screened = screened.to_vec();
+        screened.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let mut seen = std::collections::HashSet::new();
+        screened.retain(|(key, _)| seen.insert(key.clone()));
+        let emin = screened.first().map_or(0.0, |x| x.1);
+        screened
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (_, e))| *i < self.nrefine || self.energy_window.map_or(false, |w| e - emin <= w))
+            .map(|(_, (key, _))| key)
+            .collect()
+    }
+}
+// c86f0b2d ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_screening_select() -> Result<()> {
+    let mut screening: Screening = toml::from_str(r#"template_directory = "screen""#)?;
+    assert_eq!(screening.working_directory(), Path::new("jobs-screen"));
+    assert_eq!(screening.nrefine, 2);
+
+    let screened: Vec<_> = [("1100", -1.0), ("1010", -3.0), ("1001", -2.9), ("1010", -3.0), ("0110", -2.0)]
+        .iter()
+        .map(|(k, e)| (k.to_string(), *e))
+        .collect();
+    assert_eq!(screening.select(&screened), vec!["1010", "1001"]);
+    screening.nrefine = 1;
+    screening.energy_window = Some(1.0);
+    assert_eq!(screening.select(&screened), vec!["1010", "1001", "0110"]);
+    screening.nrefine = 0;
+    screening.energy_window = None;
+    assert!(screening.select(&screened).is_empty());
+    assert!(screening.select(&[]).is_empty());
+
+    // duplicated keys with different energies
+    screening.nrefine = 3;
+    let screened: Vec<_> = [("1010", -3.0), ("1001", -2.0), ("1010", -1.0)]
+        .iter()
+        .map(|(k, e)| (k.to_string(), *e))
+        .collect();
+    assert_eq!(screening.select(&screened), vec!["1010", "1001"]);
+
+    Ok(())
+}
+// test:1 ends here
@@ -2,6 +2,7 @@
 use super::*;
 use std::path::Path;
 
+use crate::spin::SpinStates;
 use spdkit::encoding::Binary;
 use spdkit::population::Population;
 use spdkit::prelude::*;
@@ -10,40 +11,85 @@ use spdkit::prelude::*;
 // [[file:../magman.note::c0ca7449][c0ca7449]]
 type MagGenome = Binary;
 
-#[derive(Debug, Clone)]
-struct MagIndividual;
+#[derive(Debug, Clone, Default)]
+struct MagIndividual {
+    /// The first evaluation error, which aborts the search after the
+    /// current generation.
+    error: std::sync::Arc<Mutex<Option<gut::prelude::Error>>>,
+}
 
 impl EvaluateObjectiveValue<MagGenome> for MagIndividual {
     fn evaluate(&self, genome: &MagGenome) -> f64 {
-        let key = genome.to_string();
-        evaluate_magmom(genome).unwrap_or_else(|e| panic!("evaluation failed with error: {:?}", e))
+        evaluate_magmom(genome).unwrap_or_else(|e| {
+            error!("evaluation failed with error: {:?}", e);
+            self.error.lock().expect("evaluation error").get_or_insert(e);
+            failure_energy()
+        })
     }
 }
 
 fn evaluate_magmom(indv: &MagGenome) -> Result<f64> {
     use crate::magmom::*;
 
-    // with the first bit fixed as spin-up.
-    let mut so = indv.clone();
-    so.insert(0, true);
-
-    // NOTE: use data in csv table for tests
-    // let csv = crate::vasp::CsvEvaluator {};
-    // let ms = csv.evaluate(&so).expect("indv eval");
-
-    let vasp = &crate::config::MAGMAN_CONFIG.vasp;
-    let ms = vasp.evaluate(&so).context("vasp evaluation")?;
+    let so = spin_ordering_from_genome(indv);
+    let evaluator = crate::config::MAGMAN_CONFIG.evaluator();
+    // failed individuals are penalized to be unlikely to survive
+    let ms = match evaluate_or_record(evaluator, &so)? {
+        Some(ms) => ms,
+        None => return Ok(failure_energy()),
+    };
+    // screening energies are comparable among all individuals
+    let energy = if crate::config::MAGMAN_CONFIG.vasp.screening().is_some() {
+        ms.screened_energy()
+    } else {
+        ms.energy
+    };
     match EVALUATED.lock() {
         Ok(mut map) => {
-            let key = so.to_string();
-            map.insert(key, ms.energy);
-            Ok(ms.energy)
+            let key = spin_key(&so);
+            map.insert(key, energy);
+            Ok(energy)
         }
         Err(err) => {
             bail!("lock map failed: {:?}", err)
         }
     }
 }
+
+/// The energy for penalizing failed individuals: `failure_energy` in
+/// config, or the highest energy evaluated so far plus `failure_margin`.
+fn failure_energy() -> f64 {
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    config.failure_energy.unwrap_or_else(|| {
+        let emax = EVALUATED.lock().unwrap().values().copied().fold(f64::NAN, f64::max);
+        // nothing to compare with before any successful evaluation
+        if emax.is_nan() {
+            f64::MAX
+        } else {
+            emax + config.failure_margin
+        }
+    })
+}
+
+/// Spin states of magnetic sites in config.
+fn spin_states() -> &'static SpinStates {
+    crate::config::MAGMAN_CONFIG.vasp.spin_states()
+}
+
+/// Decode `genome` into spin-ordering using spin states in config.
+fn spin_ordering_from_genome(genome: &MagGenome) -> Vec<u8> {
+    spin_states().decode(genome)
+}
+
+/// Return the allowed numbers of spin-up in genome, constrained by net
+/// magnetization in config.
+fn spin_up_counts() -> Result<Option<Vec<usize>>> {
+    let nups = crate::config::MAGMAN_CONFIG.search.spin_up_counts(crate::config::genome_length()?)?;
+    if nups.is_some() && !spin_states().is_binary() {
+        bail!("net magnetization can be constrained only for up/down spin states");
+    }
+    Ok(nups)
+}
 // c0ca7449 ends here
 
 // [[file:../magman.note::809ad587][809ad587]]
@@ -85,36 +131,179 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 
 use gosh::runner::stop::StopFileHandler;
-use spdkit::operators::selection::StochasticUniversalSampling as SusSelection;
-use spdkit::operators::selection::TournamentSelection;
-use spdkit::operators::variation::TriadicCrossOver;
 
 lazy_static! {
     static ref EVALUATED: Mutex<HashMap<String, f64>> = Mutex::new(HashMap::new());
+    /// Failed evaluations with the reasons.
+    static ref FAILED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Evaluate spin-ordering `so`. Failed evaluation, such as failed VASP
+/// job, is recorded and None is returned, unless `abort_on_failure` is set
+/// in config.
+fn evaluate_or_record(evaluator: &dyn crate::magmom::EvaluateMagneticState, so: &[u8]) -> Result<Option<crate::magmom::MagneticState>> {
+    match evaluator.evaluate(so) {
+        Ok(ms) => Ok(Some(ms)),
+        Err(e) if !crate::config::MAGMAN_CONFIG.search.abort_on_failure => {
+            let key = crate::magmom::spin_key(so);
+            let reason = match e.downcast_ref::<crate::failure::JobFailure>() {
+                Some(failure) => failure.to_string(),
+                None => format!("{:#}", e),
+            };
+            error!("evaluation of {} failed: {:?}", key, e);
+            FAILED.lock().unwrap().insert(key, reason);
+            Ok(None)
+        }
+        Err(e) => Err(e.context("evaluation")),
+    }
+}
+
+/// Refine the promising ones in `screened` pairs of spin-ordering key and
+/// screening energy using production template, if screening is enabled in
+/// config.
+fn refine_screened(screened: &[(String, f64)]) -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG;
+    let vasp = &config.vasp;
+    let screening = match vasp.screening() {
+        Some(screening) if config.replay.is_none() => screening,
+        _ => return Ok(()),
+    };
+    for key in screening.select(screened) {
+        use crate::magmom::EvaluateMagneticState;
+
+        let so = vasp.canonicalize(&crate::magmom::parse_spin_key(&key)?);
+        // failed refinements will not be retried
+        if let Some(failed) = crate::failure::FailedState::load(&crate::magmom::spin_key(&so))? {
+            info!("skip refinement of {} failed before: {}", key, failed.failure);
+            continue;
+        }
+        match vasp.refine(&so) {
+            Ok(ms) => println!("refined {} => {:-12.4} (screening: {:-12.4})", key, ms.energy, ms.screened_energy()),
+            Err(e) if !config.search.abort_on_failure => {
+                error!("refinement of {} failed: {:?}", key, e);
+                if let Some(failure) = e.downcast_ref::<crate::failure::JobFailure>() {
+                    if let Err(e) = crate::failure::FailedState::new(&so, failure.clone()).save() {
+                        error!("{:?}", e);
+                    }
+                }
+            }
+            Err(e) => return Err(e.context("refinement")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the lowest-energy refined item in database, if screening is
+/// enabled in config.
+fn print_refined() -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG;
+    if config.vasp.screening().is_some() && config.replay.is_none() {
+        let items = crate::magmom::MagneticState::list_all()?;
+        let refined = items.iter().filter(|ms| !ms.screening_only);
+        if let Some(ms) = refined.min_by(|a, b| a.energy.total_cmp(&b.energy)) {
+            println!("lowest refined energy: {} => {:-12.4}", ms.spin_key(), ms.energy);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the summary of failed evaluations.
+fn print_failures() {
+    let failed = FAILED.lock().unwrap();
+    if !failed.is_empty() {
+        println!("{} evaluations failed:", failed.len());
+        let mut failed: Vec<_> = failed.iter().collect();
+        failed.sort();
+        for (key, reason) in failed {
+            println!("{} => {}", key, reason);
+        }
+    }
+}
+
+/// Metadata of a search run saved in database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchRun {
+    /// The search strategy, such as "genetic" or "surrogate".
+    pub strategy: String,
+    /// The seed for random number generator.
+    pub random_seed: u64,
+    /// The time when the search started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl SearchRun {
+    /// Return all search runs in database, in chronological order.
+    pub fn list_all() -> Result<Vec<Self>> {
+        use gosh::db::prelude::*;
+
+        let mut runs = Self::list_collection(&crate::MAG_DB_CONNECTION)?;
+        runs.sort_by_key(|r| r.timestamp);
+        Ok(runs)
+    }
+}
+
+/// Seed the random number generator for `strategy` search using
+/// `random_seed` in config, or a random one if it is not set. The seed is
+/// recorded in database.
+fn setup_random_seed(strategy: &str) -> Result<u64> {
+    use gosh::db::prelude::*;
+
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let random_seed = config.random_seed.unwrap_or_else(rand::random);
+    println!("random seed = {random_seed}. Set `random_seed` in config to reproduce this search.");
+    reset_rng(random_seed);
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let run = SearchRun {
+        strategy: strategy.into(),
+        random_seed,
+        timestamp,
+    };
+    run.put_into_collection(&crate::MAG_DB_CONNECTION, &timestamp.to_string())
+        .context("save search run into db")?;
+
+    Ok(random_seed)
+}
+
+/// Reset the global random number generator used in genetic search with
+/// `seed`. Selection and crossover operators draw from the same generator.
+fn reset_rng(seed: u64) {
+    let mut rng = spdkit::get_rng!();
+    *rng = rand::SeedableRng::seed_from_u64(seed);
 }
 
 pub fn genetic_search() -> Result<()> {
     let config = &crate::config::MAGMAN_CONFIG.search;
 
-    // FIXME: genome length
-    let length = config.genome_length - 1;
+    let length = spin_states().genome_size(crate::config::genome_length()?);
 
     // create a valuer gear
     let temperature = config.boltzmann_temperature;
+    let indv = MagIndividual::default();
+    let error = indv.error.clone();
     let valuer = spdkit::Valuer::new()
         .with_fitness(spdkit::fitness::MinimizeEnergy::new(temperature))
-        .with_creator(MagIndividual);
+        .with_creator(indv);
 
     // create a breeder for new individuals
+    let (selection, variation) = crate::operators::genetic_operators(config, spin_up_counts()?, spin_states());
     let breeder = spdkit::GeneticBreeder::new()
-        .with_crossover(TriadicCrossOver)
-        .with_selector(SusSelection::new(3));
+        .with_crossover(variation)
+        .with_selector(selection);
 
     // setup the algorithm
     let algo = spdkit::EvolutionAlgorithm::new(breeder, spdkit::Survivor::create().remove_duplicates(true));
     let stop = StopFileHandler::new();
-    // FIXMEFIXMEFIXME
-    let seeds = build_initial_genomes(config.population_size, length);
+    setup_random_seed("genetic")?;
+    let seeds = if config.resume {
+        resume_initial_genomes(config.population_size, length)?
+    } else {
+        build_initial_genomes(config.population_size, length, spin_up_counts()?.as_deref(), spin_states())
+    };
     for g in spdkit::Engine::create()
         .valuer(valuer)
         .algorithm(algo)
@@ -123,7 +312,24 @@ pub fn genetic_search() -> Result<()> {
         .take(config.max_generations)
     {
         let generation = g?;
+        if let Some(e) = error.lock().unwrap().take() {
+            return Err(e);
+        }
         generation.summary();
+        save_population(&generation.population, &config.population_file)?;
+        // failed individuals are not in evaluated map
+        let screened: Vec<_> = {
+            let evaluated = EVALUATED.lock().unwrap();
+            generation
+                .population
+                .members()
+                .filter_map(|m| {
+                    let key = crate::magmom::spin_key(&spin_ordering_from_genome(m.individual.genome()));
+                    evaluated.get(&key).map(|&e| (key, e))
+                })
+                .collect()
+        };
+        refine_screened(&screened)?;
         let energy = generation.population.best_member().unwrap().objective_value();
 
         if let Some(target_energy) = config.target_energy {
@@ -156,19 +362,749 @@ pub fn genetic_search() -> Result<()> {
 
     let map = EVALUATED.lock().unwrap();
     println!("Explored {} combinations.", map.len());
+    print_failures();
+    print_refined()?;
 
     Ok(())
 }
 
-fn build_initial_genomes(n: usize, m: usize) -> Vec<Binary> {
+/// Build `n` random genomes of size `m` for `spins`. The number of spin-up
+/// in each genome will be chosen from `nups` if it is not None.
+fn build_initial_genomes(n: usize, m: usize, nups: Option<&[usize]>, spins: &SpinStates) -> Vec<Binary> {
     info!("Initialize {} genomes (genome size: {})", n, m);
 
-    (0..n).map(|_| random_binary(m)).collect()
+    match nups {
+        Some(nups) => (0..n)
+            .map(|_| {
+                let k = spdkit::get_rng!().gen_range(0..nups.len());
+                random_binary_with_count(m, nups[k])
+            })
+            .collect(),
+        None => (0..n).map(|_| random_binary(m, spins)).collect(),
+    }
 }
 
-fn random_binary(length: usize) -> Binary {
+/// Build `n` initial genomes of size `m` from population saved in previous
+/// run or from the lowest-energy items in database, filled up with random
+/// immigrants.
+fn resume_initial_genomes(n: usize, m: usize) -> Result<Vec<Binary>> {
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let nrandom = ((n as f64 * config.immigrant_fraction).round() as usize).min(n);
+
+    let keys: Vec<String> = if config.population_file.is_file() {
+        info!("Resume population from {}", config.population_file.display());
+        let txt = gut::fs::read_file(&config.population_file)?;
+        serde_json::from_str(&txt).with_context(|| format!("parse {}", config.population_file.display()))?
+    } else {
+        info!("Resume population from lowest-energy items in database.");
+        let mut items = crate::config::MAGMAN_CONFIG.evaluator().list_evaluated()?;
+        // only production energies of requested spin-orderings are comparable
+        items.retain(|ms| !ms.spin_flipped && !ms.screening_only);
+        items.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+        items.iter().map(|ms| ms.spin_key()).collect()
+    };
+
+    let nups = spin_up_counts()?;
+    let allowed = |g: &Binary| nups.as_ref().map_or(true, |nups| nups.contains(&crate::operators::count_spin_up(g)));
+    let mut seeds: Vec<Binary> = vec![];
+    let nsites = crate::config::genome_length()?;
+    for genome in keys
+        .iter()
+        .filter_map(|key| genome_from_key(key, nsites, spin_states()))
+        .filter(allowed)
+    {
+        if seeds.len() + nrandom >= n {
+            break;
+        }
+        if !seeds.contains(&genome) {
+            seeds.push(genome);
+        }
+    }
+    info!("Resumed {} genomes, with {} random immigrants.", seeds.len(), n - seeds.len());
+    seeds.extend(build_initial_genomes(n - seeds.len(), m, nups.as_deref(), spin_states()));
+
+    Ok(seeds)
+}
+
+/// Convert key of spin-ordering of `n` magnetic sites into genome using
+/// `spins`.
+fn genome_from_key(key: &str, n: usize, spins: &SpinStates) -> Option<Binary> {
+    match crate::magmom::parse_spin_key(key) {
+        Ok(so) if so.len() == n && spins.check_spin_ordering(&so).is_ok() => Some(Binary::new(spins.encode(&so))),
+        _ => {
+            warn!("ignored key {} for {} magnetic sites", key, n);
+            None
+        }
+    }
+}
+
+/// Save spin-orderings of `population` into file `path`, so that an
+/// interrupted search can be resumed.
+fn save_population(population: &Population<Binary>, path: &Path) -> Result<()> {
+    let keys: Vec<_> = population
+        .members()
+        .map(|m| crate::magmom::spin_key(&spin_ordering_from_genome(m.individual.genome())))
+        .collect();
+    let txt = serde_json::to_string_pretty(&keys)?;
+    gut::fs::write_to_file(path, &txt).with_context(|| format!("save population into {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Random genome of size `length`, with invalid codes of `spins` redrawn.
+fn random_binary(length: usize, spins: &SpinStates) -> Binary {
+    let mut rng = spdkit::get_rng!();
+    let mut list: Vec<_> = (0..length).map(|_| rng.gen()).collect();
+    spins.repair(&mut list, &mut *rng);
+    Binary::new(list)
+}
+
+/// Random genome of size `length` with `nup` spin-up.
+fn random_binary_with_count(length: usize, nup: usize) -> Binary {
     let mut rng = spdkit::get_rng!();
-    let list: Vec<_> = (0..length).map(|_| rng.gen()).collect();
+    let mut list = vec![false; length];
+    for i in rand::seq::index::sample(&mut *rng, length, nup.min(length)) {
+        list[i] = true;
+    }
     Binary::new(list)
 }
 // 2bff375c ends here
+
+// [[file:../magman.note::b81f2c47][b81f2c47]]
+use crate::exchange::{ExchangeModel, NeighborShells};
+
+/// Spin-ordering evaluated by a fitted exchange model instead of VASP.
+#[derive(Debug, Clone)]
+struct SurrogateIndividual {
+    model: ExchangeModel,
+}
+
+impl EvaluateObjectiveValue<MagGenome> for SurrogateIndividual {
+    fn evaluate(&self, genome: &MagGenome) -> f64 {
+        self.model.predict(&spin_ordering_from_genome(genome))
+    }
+}
+
+/// The max number of random tries for each spin-ordering required in
+/// initial fitting of surrogate model.
+const MAX_INITIAL_TRIES_PER_ITEM: usize = 10;
+
+/// Search the ground-state spin-ordering assisted by a surrogate exchange
+/// model. In each cycle, the model is refit on all items in database, then
+/// genetic search is performed against the cheap model, and only the top
+/// candidates are verified by VASP. The search stops when the predicted
+/// ground state has already been verified, or no new candidate is verified
+/// in a cycle.
+pub fn surrogate_search() -> Result<()> {
+    use crate::magmom::*;
+
+    let config = &crate::config::MAGMAN_CONFIG;
+    let vasp = &config.vasp;
+    let evaluator = config.evaluator();
+    let n = crate::config::genome_length()?;
+    let poscar = crate::poscar::Poscar::from_file(vasp.template_directory().join("POSCAR"))?;
+    let sites = &crate::config::magnetic_sites()?.sites;
+    let shells = NeighborShells::from_poscar(&poscar, sites, config.surrogate.nshells)?;
+
+    if vasp.screening().is_some() && config.replay.is_none() {
+        bail!("screening is not supported in surrogate search yet");
+    }
+    setup_random_seed("surrogate")?;
+    // make sure there are enough distinct items for fitting, skipping
+    // failed, spin-flipped and already known spin-orderings
+    let nmin = config.surrogate.nshells + 2;
+    let mut usable: std::collections::HashSet<_> = evaluator
+        .list_evaluated()?
+        .iter()
+        .filter(|ms| !ms.spin_flipped)
+        .map(|ms| ms.spin_key())
+        .collect();
+    if usable.len() < nmin {
+        info!("Evaluate random spin-orderings for initial fitting: {} of {} found.", usable.len(), nmin);
+    }
+    let m = spin_states().genome_size(n);
+    let max_tries = MAX_INITIAL_TRIES_PER_ITEM * nmin;
+    let mut ntries = 0;
+    while usable.len() < nmin || ExchangeModel::fit(shells.clone(), spin_states(), &evaluator.list_evaluated()?).is_err() {
+        if ntries >= max_tries {
+            bail!(
+                "no valid exchange model fitted using {} distinct spin-orderings after {} random tries",
+                usable.len(),
+                ntries
+            );
+        }
+        ntries += 1;
+        let genome = &build_initial_genomes(1, m, spin_up_counts()?.as_deref(), spin_states())[0];
+        let so = evaluator.canonicalize(&spin_ordering_from_genome(genome));
+        if usable.contains(&spin_key(&so)) {
+            continue;
+        }
+        if let Some(ms) = evaluate_or_record(evaluator, &so)? {
+            if !ms.spin_flipped {
+                usable.insert(ms.spin_key());
+            }
+        }
+    }
+
+    let stop = StopFileHandler::new();
+    for icycle in 0..config.surrogate.max_cycles {
+        let states = evaluator.list_evaluated()?;
+        let model = ExchangeModel::fit(shells.clone(), spin_states(), &states)?;
+        let verified: HashMap<_, _> = states.iter().map(|ms| (ms.spin_key(), ms.energy)).collect();
+        let nflipped = states.iter().filter(|ms| ms.spin_flipped).count();
+        println!("cycle {}: exchange model fitted using {} items.", icycle, states.len() - nflipped);
+
+        // failed or spin-flipped spin-orderings can not be verified
+        let mut failed: std::collections::HashSet<_> = crate::failure::FailedState::list_all()?
+            .iter()
+            .map(|x| spin_key(&x.spin_ordering))
+            .collect();
+        failed.extend(FAILED.lock().unwrap().keys().cloned());
+        failed.extend(states.iter().filter(|ms| ms.spin_flipped).map(|ms| ms.spin_key()));
+        let mut candidates = evolve_surrogate(&model, evaluator)?;
+        candidates.retain(|(key, _)| !failed.contains(key));
+        let (best_key, best_energy) = candidates.first().context("no candidate left")?;
+        println!("predicted ground state: {} => {:-12.4}", best_key, best_energy);
+        if let Some(energy) = verified.get(best_key) {
+            println!("predicted ground state has been verified by VASP: {:-12.4}", energy);
+            break;
+        }
+
+        let mut nverified = 0;
+        for (key, _) in candidates.iter().filter(|(key, _)| !verified.contains_key(key)).take(config.surrogate.top_k) {
+            let so = parse_spin_key(key)?;
+            let ms = match evaluate_or_record(evaluator, &so)? {
+                Some(ms) => ms,
+                None => continue,
+            };
+            nverified += 1;
+            println!("verified {} => {:-12.4} (predicted: {:-12.4})", key, ms.energy, model.predict(&so));
+        }
+        if nverified == 0 {
+            println!("no new spin-ordering verified in cycle {}.", icycle);
+            break;
+        }
+        stop.handle_user_interruption()?;
+    }
+
+    let mut states = evaluator.list_evaluated()?;
+    println!("Explored {} combinations.", states.len());
+    print_failures();
+    print_energy_spectrum(&mut states);
+
+    Ok(())
+}
+
+/// Evolve genetic algorithm against the exchange `model`, returning unique
+/// candidates sorted by predicted energy.
+fn evolve_surrogate(model: &ExchangeModel, evaluator: &dyn crate::magmom::EvaluateMagneticState) -> Result<Vec<(String, f64)>> {
+    use crate::magmom::*;
+
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let length = spin_states().genome_size(crate::config::genome_length()?);
+
+    let valuer = spdkit::Valuer::new()
+        .with_fitness(spdkit::fitness::MinimizeEnergy::new(config.boltzmann_temperature))
+        .with_creator(SurrogateIndividual { model: model.clone() });
+    let (selection, variation) = crate::operators::genetic_operators(config, spin_up_counts()?, spin_states());
+    let breeder = spdkit::GeneticBreeder::new()
+        .with_crossover(variation)
+        .with_selector(selection);
+    let algo = spdkit::EvolutionAlgorithm::new(breeder, spdkit::Survivor::create().remove_duplicates(true));
+
+    let mut candidates = HashMap::new();
+    let seeds = build_initial_genomes(config.population_size, length, spin_up_counts()?.as_deref(), spin_states());
+    for g in spdkit::Engine::create()
+        .valuer(valuer)
+        .algorithm(algo)
+        .termination_nlast(config.termination_nlast)
+        .evolve(&seeds)
+        .take(crate::config::MAGMAN_CONFIG.surrogate.max_generations)
+    {
+        let generation = g?;
+        for m in generation.population.members() {
+            let so = spin_ordering_from_genome(m.individual.genome());
+            let key = spin_key(&evaluator.canonicalize(&so));
+            candidates.insert(key, m.objective_value());
+        }
+    }
+
+    let mut candidates: Vec<_> = candidates.into_iter().collect();
+    if candidates.is_empty() {
+        bail!("no candidates found by surrogate search");
+    }
+    candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    Ok(candidates)
+}
+// b81f2c47 ends here
+
+// [[file:../magman.note::3e5b7d1a][3e5b7d1a]]
+/// The max number of magnetic sites for exhaustive enumeration without
+/// setting `max_evaluations` explicitly.
+const MAX_ENUMERATION_SITES: usize = 16;
+
+/// Search the ground-state spin-ordering by evaluating all possible
+/// combinations of spin-orderings in a brute-force way.
+pub fn exhaustive_search() -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let vasp = &crate::config::MAGMAN_CONFIG.vasp;
+    let evaluator = crate::config::MAGMAN_CONFIG.evaluator();
+
+    let n = crate::config::genome_length()?;
+    if n == 0 || n >= 64 {
+        bail!("invalid genome length: {n}");
+    }
+    // with the first bit fixed as spin-up
+    let spins = spin_states();
+    let nups = spin_up_counts()?;
+    let total = count_spin_orderings(n, spins, nups.as_deref());
+    if total > 1u64 << (MAX_ENUMERATION_SITES - 1) && config.max_evaluations.is_none() {
+        bail!("too many combinations ({total}) for {n} magnetic sites; please set max_evaluations in config.");
+    }
+    let max_evaluations = config.max_evaluations.map(|m| m as u64).unwrap_or(total).min(total);
+    info!("Enumerate {max_evaluations} of {total} spin-orderings for {n} magnetic sites.");
+
+    let stop = StopFileHandler::new();
+    let mut states = vec![];
+    // symmetry-equivalent spin-orderings will be evaluated only once
+    let mut explored = std::collections::HashSet::new();
+    // failed evaluations also count
+    let mut nevaluated = 0;
+    for so in enumerate_allowed_spin_orderings(n, spins, nups) {
+        if !explored.insert(evaluator.canonicalize(&so)) {
+            continue;
+        }
+        if nevaluated >= max_evaluations {
+            println!("The maximum allowed combinations have been explored. Stop now.");
+            break;
+        }
+        nevaluated += 1;
+        let ms = match evaluate_or_record(evaluator, &so)? {
+            Some(ms) => ms,
+            None => continue,
+        };
+        debug!("{}/{} ==> {}", nevaluated, max_evaluations, ms.spin_key());
+        let energy = ms.energy;
+        states.push(ms);
+
+        if let Some(target_energy) = config.target_energy {
+            if energy < target_energy {
+                println!("target energy {} reached.", target_energy);
+                break;
+            }
+        }
+        stop.handle_user_interruption()?;
+    }
+
+    println!("Explored {} of {} combinations, {} succeeded.", nevaluated, total, states.len());
+    print_failures();
+    let screened: Vec<_> = states.iter().map(|ms| (ms.spin_key(), ms.screened_energy())).collect();
+    if vasp.screening().is_some() {
+        println!("Energy spectrum from screening calculations:");
+        states.iter_mut().for_each(|ms| ms.energy = ms.screened_energy());
+    }
+    print_energy_spectrum(&mut states);
+    refine_screened(&screened)?;
+    print_refined()?;
+
+    Ok(())
+}
+
+/// Enumerate all spin-orderings of `n` magnetic sites, with the first site
+/// fixed as spin-up.
+fn enumerate_spin_orderings(n: usize) -> impl Iterator<Item = Vec<u8>> {
+    let m = n - 1;
+    (0..1u64 << m).map(move |i| spin_ordering_from_bits(i, m))
+}
+
+/// Enumerate spin-orderings of `n` magnetic sites with the first site fixed
+/// as spin-up, and `k` spin-up among the other sites for each `k` in `nups`.
+fn enumerate_constrained_spin_orderings(n: usize, nups: Vec<usize>) -> impl Iterator<Item = Vec<u8>> {
+    let m = n - 1;
+    nups.into_iter().filter(move |&k| k <= m).flat_map(move |k| {
+        // walk through all m-bit integers with k bits set (Gosper's hack)
+        let first = (1u64 << k) - 1;
+        std::iter::successors(Some(first), move |&x| {
+            if x == 0 {
+                return None;
+            }
+            let c = x & x.wrapping_neg();
+            let r = x + c;
+            let next = (((r ^ x) >> 2) / c) | r;
+            if next >> m == 0 {
+                Some(next)
+            } else {
+                None
+            }
+        })
+        .map(move |i| spin_ordering_from_bits(i, m))
+    })
+}
+
+/// Enumerate spin-orderings of `n` magnetic sites in multiple spin states
+/// `spins`, skipping the ones equivalent by reversing all spins.
+fn enumerate_multistate_spin_orderings(n: usize, spins: &SpinStates) -> impl Iterator<Item = Vec<u8>> {
+    let q = spins.nstates() as u64;
+    let spins = spins.clone();
+    (0..q.checked_pow(n as u32).unwrap_or(u64::MAX))
+        .map(move |mut i| {
+            let mut so = vec![0; n];
+            for s in so.iter_mut().rev() {
+                *s = (i % q) as u8;
+                i /= q;
+            }
+            so
+        })
+        .filter(move |so| &spins.normalize(so) == so)
+}
+
+/// Enumerate spin-orderings of `n` magnetic sites in `spins`, constrained
+/// by the allowed numbers of spin-up `nups` if it is not None.
+fn enumerate_allowed_spin_orderings(
+    n: usize,
+    spins: &SpinStates,
+    nups: Option<Vec<usize>>,
+) -> Box<dyn Iterator<Item = Vec<u8>>> {
+    match nups {
+        _ if !spins.is_binary() => Box::new(enumerate_multistate_spin_orderings(n, spins)),
+        Some(nups) => Box::new(enumerate_constrained_spin_orderings(n, nups)),
+        None => Box::new(enumerate_spin_orderings(n)),
+    }
+}
+
+/// Return the number of spin-orderings of `n` magnetic sites in `spins`
+/// with the first site fixed as spin-up, constrained by `nups` if it is not
+/// None. For multiple spin states, spin-reversed ones are also counted.
+fn count_spin_orderings(n: usize, spins: &SpinStates, nups: Option<&[usize]>) -> u64 {
+    let m = n - 1;
+    match nups {
+        _ if !spins.is_binary() => (spins.nstates() as u64).checked_pow(n as u32).unwrap_or(u64::MAX),
+        Some(nups) => nups.iter().filter(|&&k| k <= m).map(|&k| binomial(m, k)).sum(),
+        None => 1u64 << m,
+    }
+}
+
+fn binomial(n: usize, k: usize) -> u64 {
+    let k = k.min(n - k);
+    (0..k).fold(1u64, |acc, i| acc * (n - i) as u64 / (i + 1) as u64)
+}
+
+/// Convert the lowest `m` bits of `i` into up/down spin-ordering, with an
+/// extra spin-up site prepended.
+fn spin_ordering_from_bits(i: u64, m: usize) -> Vec<u8> {
+    let mut so = vec![1];
+    so.extend((0..m).rev().map(|j| ((i >> j) & 1) as u8));
+    so
+}
+
+/// Print ranked energy spectrum of magnetic states `states`.
+fn print_energy_spectrum(states: &mut [crate::magmom::MagneticState]) {
+    if states.is_empty() {
+        return;
+    }
+    states.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+    let e0 = states[0].energy;
+    let width = states[0].spin_ordering.len();
+    println!("{:>5} {:^width$} {:^14} {:^12}", "rank", "key", "energy", "relative", width = width);
+    for (i, ms) in states.iter().enumerate() {
+        println!("{:>5} {} {:-14.5} {:-12.5}", i + 1, ms.spin_key(), ms.energy, ms.energy - e0);
+    }
+}
+// 3e5b7d1a ends here
+
+// [[file:../magman.note::6d2a9e31][6d2a9e31]]
+use crate::replay::ReplayTable;
+use std::sync::Arc;
+
+/// Energies within this tolerance to the ground state are treated as found.
+const GROUND_STATE_TOLERANCE: f64 = 1e-6;
+
+/// Spin-ordering evaluated by a replay table, recording all distinct
+/// evaluations without touching the database.
+#[derive(Debug, Clone)]
+struct ReplayIndividual {
+    table: Arc<ReplayTable>,
+    /// Energies of evaluated spin-orderings by canonical key.
+    evaluated: Arc<Mutex<HashMap<String, f64>>>,
+    /// The first evaluation error, which is reported after each generation.
+    error: Arc<Mutex<Option<gut::prelude::Error>>>,
+    /// The energy assigned to individuals failed in evaluation.
+    penalty: f64,
+    /// The ground state energy in table.
+    e_gs: f64,
+    /// The number of distinct evaluations when the ground state is found.
+    found: Arc<Mutex<Option<usize>>>,
+}
+
+impl EvaluateObjectiveValue<MagGenome> for ReplayIndividual {
+    fn evaluate(&self, genome: &MagGenome) -> f64 {
+        use crate::magmom::EvaluateMagneticState;
+
+        let so = spin_ordering_from_genome(genome);
+        match self.table.evaluate_new(&so) {
+            Ok(ms) => {
+                let key = crate::magmom::spin_key(&self.table.canonicalize(&so));
+                let mut evaluated = self.evaluated.lock().expect("evaluated map");
+                if evaluated.insert(key, ms.energy).is_none() && ms.energy < self.e_gs + GROUND_STATE_TOLERANCE {
+                    self.found.lock().expect("ground state").get_or_insert(evaluated.len());
+                }
+                ms.energy
+            }
+            Err(e) => {
+                self.error.lock().expect("evaluation error").get_or_insert(e);
+                self.penalty
+            }
+        }
+    }
+}
+
+/// The result of searching with one random seed in benchmark.
+#[derive(Debug, Clone)]
+struct BenchRun {
+    strategy: &'static str,
+    seed: u64,
+    /// The number of evaluations required to find the ground state, or
+    /// None if not found.
+    evaluations: Option<usize>,
+    /// The total number of evaluations.
+    total: usize,
+    best_energy: f64,
+}
+
+/// Run genetic search against replay `table` with random `seed`.
+fn bench_genetic_search(table: &Arc<ReplayTable>, seed: u64, e_gs: f64) -> Result<BenchRun> {
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let length = spin_states().genome_size(crate::config::genome_length()?);
+    reset_rng(seed);
+
+    let evaluated = Arc::new(Mutex::new(HashMap::new()));
+    let error = Arc::new(Mutex::new(None));
+    let found = Arc::new(Mutex::new(None));
+    let valuer = spdkit::Valuer::new()
+        .with_fitness(spdkit::fitness::MinimizeEnergy::new(config.boltzmann_temperature))
+        .with_creator(ReplayIndividual {
+            table: table.clone(),
+            evaluated: evaluated.clone(),
+            error: error.clone(),
+            penalty: table.highest_energy().unwrap_or(e_gs),
+            e_gs,
+            found: found.clone(),
+        });
+    let (selection, variation) = crate::operators::genetic_operators(config, spin_up_counts()?, spin_states());
+    let breeder = spdkit::GeneticBreeder::new()
+        .with_crossover(variation)
+        .with_selector(selection);
+    let algo = spdkit::EvolutionAlgorithm::new(breeder, spdkit::Survivor::create().remove_duplicates(true));
+
+    let seeds = build_initial_genomes(config.population_size, length, spin_up_counts()?.as_deref(), spin_states());
+    let mut evaluations = None;
+    let mut best_energy = f64::INFINITY;
+    for g in spdkit::Engine::create()
+        .valuer(valuer)
+        .algorithm(algo)
+        .termination_nlast(config.termination_nlast)
+        .evolve(&seeds)
+        .take(config.max_generations)
+    {
+        let generation = g?;
+        if let Some(e) = error.lock().unwrap().take() {
+            return Err(e.context("replay evaluation"));
+        }
+        let energy = generation.population.best_member().unwrap().objective_value();
+        best_energy = best_energy.min(energy);
+        // counted up to the evaluation finding the ground state, not the
+        // whole generation
+        if let Some(n) = *found.lock().unwrap() {
+            evaluations = Some(n);
+            break;
+        }
+    }
+    let total = evaluated.lock().unwrap().len();
+
+    Ok(BenchRun {
+        strategy: "ga",
+        seed,
+        evaluations,
+        total,
+        best_energy,
+    })
+}
+
+/// Evaluate random spin-orderings without replacement against replay
+/// `table` with random `seed`, which is the brute-force search in random
+/// order.
+fn bench_random_search(table: &ReplayTable, seed: u64, e_gs: f64) -> Result<BenchRun> {
+    use crate::magmom::EvaluateMagneticState;
+    use rand::seq::SliceRandom;
+
+    let config = &crate::config::MAGMAN_CONFIG.search;
+    let n = crate::config::genome_length()?;
+    if n == 0 || n > MAX_ENUMERATION_SITES {
+        bail!("too many combinations for random search over {n} magnetic sites");
+    }
+    let mut orderings: Vec<_> = enumerate_allowed_spin_orderings(n, spin_states(), spin_up_counts()?).collect();
+    let mut rng: rand::rngs::StdRng = rand::SeedableRng::seed_from_u64(seed);
+    orderings.shuffle(&mut rng);
+    let max_evaluations = config.max_evaluations.unwrap_or(orderings.len());
+
+    let mut evaluations = None;
+    let mut best_energy = f64::INFINITY;
+    let mut total = 0;
+    for so in orderings.iter().take(max_evaluations) {
+        let ms = table.evaluate_new(so)?;
+        total += 1;
+        best_energy = best_energy.min(ms.energy);
+        if ms.energy < e_gs + GROUND_STATE_TOLERANCE {
+            evaluations = Some(total);
+            break;
+        }
+    }
+
+    Ok(BenchRun {
+        strategy: "random",
+        seed,
+        evaluations,
+        total,
+        best_energy,
+    })
+}
+
+/// Benchmark search `strategies` ("ga" or "random") against replay table
+/// in `table_file` (or the replay table in config if it is None) using
+/// `nseeds` distinct random seeds starting from `seed`. The number of
+/// evaluations required to find the ground state in each run is reported.
+pub fn benchmark(strategies: &[String], table_file: Option<&Path>, nseeds: usize, seed: u64) -> Result<()> {
+    let config = &crate::config::MAGMAN_CONFIG;
+    let missing_energy = config.replay.as_ref().and_then(|r| r.missing_energy);
+    let table_file = match (table_file, &config.replay) {
+        (Some(f), _) => f,
+        (None, Some(replay)) => replay.table.as_path(),
+        (None, None) => bail!("no replay table specified for benchmark"),
+    };
+    let table = Arc::new(ReplayTable::from_file(table_file, missing_energy, spin_states())?);
+    let (gs_key, e_gs) = match table.ground_state() {
+        Some((key, e)) => (key.to_string(), e),
+        None => bail!("empty replay table: {}", table_file.display()),
+    };
+    println!("Benchmark against {} items in {}", table.len(), table_file.display());
+    println!("ground state: {} => {:-12.5}", gs_key, e_gs);
+
+    let mut runs = vec![];
+    println!("{:^8} {:^8} {:^8} {:^8} {:^14}", "strategy", "seed", "nevals", "total", "best energy");
+    for strategy in strategies {
+        for seed in seed..seed + nseeds as u64 {
+            let run = match strategy.as_str() {
+                "ga" => bench_genetic_search(&table, seed, e_gs)?,
+                "random" => bench_random_search(&table, seed, e_gs)?,
+                _ => bail!("unknown search strategy: {strategy}"),
+            };
+            let nevals = run.evaluations.map_or("-".to_string(), |n| n.to_string());
+            println!("{:^8} {:^8} {:^8} {:^8} {:-14.5}", run.strategy, run.seed, nevals, run.total, run.best_energy);
+            runs.push(run);
+        }
+    }
+
+    println!("{:^8} {:^12} {:^10} {:^10}", "strategy", "success", "mean", "median");
+    for strategy in strategies {
+        let selected: Vec<_> = runs.iter().filter(|r| r.strategy == strategy.as_str()).collect();
+        let mut evaluations: Vec<_> = selected.iter().filter_map(|r| r.evaluations).collect();
+        let (mean, median) = mean_and_median(&mut evaluations);
+        println!(
+            "{:^8} {:^12} {:^10.1} {:^10.1}",
+            strategy,
+            format!("{}/{}", evaluations.len(), selected.len()),
+            mean,
+            median
+        );
+    }
+
+    Ok(())
+}
+
+/// Return mean and median of `values`, or NaN if it is empty.
+fn mean_and_median(values: &mut [usize]) -> (f64, f64) {
+    let n = values.len();
+    if n == 0 {
+        return (f64::NAN, f64::NAN);
+    }
+    values.sort_unstable();
+    let mean = values.iter().sum::<usize>() as f64 / n as f64;
+    let median = if n % 2 == 0 {
+        (values[n / 2 - 1] + values[n / 2]) as f64 / 2.0
+    } else {
+        values[n / 2] as f64
+    };
+    (mean, median)
+}
+// 6d2a9e31 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_enumerate_spin_orderings() {
+    let orderings: Vec<_> = enumerate_spin_orderings(4).collect();
+    assert_eq!(orderings.len(), 8);
+    assert!(orderings.iter().all(|so| so.len() == 4 && so[0] == 1));
+    assert_eq!(orderings[0], vec![1, 0, 0, 0]);
+    assert_eq!(orderings[1], vec![1, 0, 0, 1]);
+    assert_eq!(orderings[7], vec![1, 1, 1, 1]);
+
+    let keys: std::collections::HashSet<_> = orderings.iter().map(|so| crate::magmom::spin_key(so)).collect();
+    assert_eq!(keys.len(), 8);
+
+    // orderings of 6 sites with 3 or 4 spin-up in total
+    let orderings: Vec<_> = enumerate_constrained_spin_orderings(6, vec![2, 3]).collect();
+    let spins = SpinStates::default();
+    assert_eq!(orderings.len() as u64, count_spin_orderings(6, &spins, Some(&[2, 3])));
+    assert_eq!(orderings.len(), 10 + 10);
+    assert!(orderings.iter().all(|so| so[0] == 1 && [3, 4].contains(&so.iter().filter(|&&s| s == 1).count())));
+    let keys: std::collections::HashSet<_> = orderings.iter().map(|so| crate::magmom::spin_key(so)).collect();
+    assert_eq!(keys.len(), 20);
+    assert_eq!(enumerate_constrained_spin_orderings(4, vec![0, 3]).count(), 2);
+
+    // up, down and zero: (3^3 - 1) / 2 pairs of reversed orderings and the
+    // nonmagnetic one
+    let spins = SpinStates::new(vec![-1.0, 1.0, 0.0]).unwrap();
+    let orderings: Vec<_> = enumerate_allowed_spin_orderings(3, &spins, None).collect();
+    assert_eq!(orderings.len(), 14);
+    assert!(orderings.contains(&vec![2, 2, 2]));
+    assert_eq!(count_spin_orderings(3, &spins, None), 27);
+}
+
+#[test]
+fn test_genome_from_key() {
+    let spins = SpinStates::default();
+    let g = genome_from_key("1010", 4, &spins).unwrap();
+    assert_eq!(g.to_string(), "010");
+    // equivalent spin-ordering with all spins reversed
+    let g = genome_from_key("0010", 4, &spins).unwrap();
+    assert_eq!(g.to_string(), "101");
+    assert!(genome_from_key("10100", 4, &spins).is_none());
+    assert!(genome_from_key("1020", 4, &spins).is_none());
+}
+
+#[test]
+fn test_reset_rng() {
+    let spins = SpinStates::default();
+    reset_rng(42);
+    let g1 = build_initial_genomes(4, 20, None, &spins);
+    reset_rng(42);
+    let g2 = build_initial_genomes(4, 20, None, &spins);
+    assert_eq!(g1, g2);
+
+    let genomes = build_initial_genomes(10, 20, Some(&[3, 5]), &spins);
+    assert!(genomes.iter().all(|g| [3, 5].contains(&crate::operators::count_spin_up(g))));
+
+    // no invalid codes for three spin states in two bits
+    let spins = SpinStates::new(vec![-1.0, 1.0, 0.0]).unwrap();
+    let genomes = build_initial_genomes(10, 20, None, &spins);
+    assert!(genomes.iter().all(|g| spins.check_spin_ordering(&spins.decode(g)).is_ok()));
+}
+
+#[test]
+fn test_mean_and_median() {
+    assert_eq!(mean_and_median(&mut [5, 1, 3]), (3.0, 3.0));
+    assert_eq!(mean_and_median(&mut [4, 1, 3, 8]), (4.0, 3.5));
+    assert!(mean_and_median(&mut []).0.is_nan());
+}
+// test:1 ends here
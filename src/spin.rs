@@ -0,0 +1,287 @@
+// [[file:../magman.note::3f6b2e0a][3f6b2e0a]]
+use super::*;
+// 3f6b2e0a ends here
+
+// [[file:../magman.note::b5d47c81][b5d47c81]]
+/// The max number of spin states, limited by one-digit keys in base 36.
+const MAX_STATES: usize = 36;
+
+/// Spin states available on each magnetic site, given as initial magnetic
+/// moments relative to `initial_magmom_value`. The state index is used as
+/// the digit of each site in keys. The default states are `[-1.0, 1.0]`,
+/// so that `0` stands for spin-down and `1` for spin-up.
+///
+/// A spin state can also be given as a 3-vector in the SAXIS frame for
+/// non-collinear calculations, such as `[1.0, 0.0, 0.0]` for the moment
+/// perpendicular to the quantization axis. A scalar `v` is the same as
+/// `[0.0, 0.0, v]`, i.e. along the quantization axis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<SpinState>", into = "Vec<SpinState>")]
+pub struct SpinStates(Vec<[f64; 3]>);
+
+/// Relative moment of a spin state in config file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SpinState {
+    Collinear(f64),
+    Vector([f64; 3]),
+}
+
+impl From<SpinState> for [f64; 3] {
+    fn from(state: SpinState) -> Self {
+        match state {
+            SpinState::Collinear(v) => [0.0, 0.0, v],
+            SpinState::Vector(v) => v,
+        }
+    }
+}
+
+impl TryFrom<Vec<SpinState>> for SpinStates {
+    type Error = gut::prelude::Error;
+
+    fn try_from(states: Vec<SpinState>) -> Result<Self> {
+        Self::from_vectors(states.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<SpinStates> for Vec<SpinState> {
+    fn from(spins: SpinStates) -> Self {
+        let collinear = spins.is_collinear();
+        spins
+            .0
+            .into_iter()
+            .map(|v| if collinear { SpinState::Collinear(v[2]) } else { SpinState::Vector(v) })
+            .collect()
+    }
+}
+
+impl Default for SpinStates {
+    fn default() -> Self {
+        Self::collinear(&[-1.0, 1.0])
+    }
+}
+
+impl SpinStates {
+    /// Construct from relative moments of each spin state along the
+    /// quantization axis.
+    pub fn new(values: Vec<f64>) -> Result<Self> {
+        let states = Self::collinear(&values);
+        states.check()?;
+        Ok(states)
+    }
+
+    /// Construct from relative moment vectors of each spin state in the
+    /// SAXIS frame.
+    pub fn from_vectors(vectors: Vec<[f64; 3]>) -> Result<Self> {
+        let states = Self(vectors);
+        states.check()?;
+        Ok(states)
+    }
+
+    fn collinear(values: &[f64]) -> Self {
+        Self(values.iter().map(|&v| [0.0, 0.0, v]).collect())
+    }
+
+    /// Check if spin states are valid.
+    fn check(&self) -> Result<()> {
+        let n = self.0.len();
+        if !(2..=MAX_STATES).contains(&n) {
+            bail!("the number of spin states should be in 2..={}, found {}", MAX_STATES, n);
+        }
+        for (i, v) in self.0.iter().enumerate() {
+            if self.0[..i].contains(v) {
+                bail!("duplicated spin state: {:?}", v);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the number of spin states.
+    pub fn nstates(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Return relative moment of spin state `s` projected on the
+    /// quantization axis.
+    pub fn value(&self, s: u8) -> f64 {
+        self.0[s as usize][2]
+    }
+
+    /// Return relative moment vector of spin state `s` in the SAXIS frame.
+    pub fn moment(&self, s: u8) -> [f64; 3] {
+        self.0[s as usize]
+    }
+
+    /// Return true if all spin states are along the quantization axis.
+    pub fn is_collinear(&self) -> bool {
+        self.0.iter().all(|v| v[0] == 0.0 && v[1] == 0.0)
+    }
+
+    /// Return true for two states of reversed spins, such as up and down.
+    pub fn is_binary(&self) -> bool {
+        self.0.len() == 2 && self.0[0] == neg(self.0[1])
+    }
+
+    /// Return the state with all spins reversed of each spin state, or None
+    /// if any of them has no reversed counterpart.
+    fn reversal(&self) -> Option<Vec<u8>> {
+        self.0
+            .iter()
+            .map(|&v| self.0.iter().position(|&w| w == neg(v)).map(|i| i as u8))
+            .collect()
+    }
+
+    /// Return spin-ordering `so` with all spins reversed, or None if it is
+    /// not possible with these spin states.
+    pub fn reverse(&self, so: &[u8]) -> Option<Vec<u8>> {
+        let reversal = self.reversal()?;
+        Some(so.iter().map(|&s| reversal[s as usize]).collect())
+    }
+
+    /// Return the representative of `so` and its spin-reversed counterpart,
+    /// which has the same energy.
+    pub fn normalize(&self, so: &[u8]) -> Vec<u8> {
+        match self.reverse(so) {
+            Some(rev) if rev.as_slice() > so => rev,
+            _ => so.to_vec(),
+        }
+    }
+
+    /// Return error if any state in spin-ordering `so` is out of range.
+    pub fn check_spin_ordering(&self, so: &[u8]) -> Result<()> {
+        if let Some(&s) = so.iter().find(|&&s| s as usize >= self.nstates()) {
+            bail!("invalid spin state {} for {} spin states", s, self.nstates());
+        }
+        Ok(())
+    }
+
+    /// The number of genome bits for each site, which is 1 for up/down
+    /// spins.
+    pub fn bits_per_site(&self) -> usize {
+        let n = self.nstates();
+        (usize::BITS - (n - 1).leading_zeros()) as usize
+    }
+
+    /// Return the genome size for `nsites` magnetic sites. For binary spin
+    /// states the first site is fixed as spin-up, and one bit is used for
+    /// each other site. Otherwise each site is encoded in the least bits
+    /// that can hold all states.
+    pub fn genome_size(&self, nsites: usize) -> usize {
+        if self.is_binary() {
+            nsites - 1
+        } else {
+            nsites * self.bits_per_site()
+        }
+    }
+
+    /// Decode `genome` into spin-ordering. The genome should be repaired
+    /// with `repair` first, otherwise out of range codes are kept as
+    /// invalid states, which are rejected by `check_spin_ordering`.
+    pub fn decode(&self, genome: &[bool]) -> Vec<u8> {
+        if self.is_binary() {
+            let mut so = vec![1];
+            so.extend(genome.iter().map(|&b| b as u8));
+            so
+        } else {
+            genome.chunks(self.bits_per_site()).map(|bits| decode_bits(bits) as u8).collect()
+        }
+    }
+
+    /// Redraw out of range codes in `genome` uniformly from all spin
+    /// states, so that each state is sampled with the same probability.
+    pub fn repair<R: rand::Rng + ?Sized>(&self, genome: &mut [bool], rng: &mut R) {
+        if self.is_binary() {
+            return;
+        }
+        let m = self.bits_per_site();
+        for bits in genome.chunks_mut(m) {
+            if decode_bits(bits) >= self.nstates() {
+                let s = rng.gen_range(0..self.nstates());
+                for (j, b) in bits.iter_mut().enumerate() {
+                    *b = (s >> (m - 1 - j)) & 1 == 1;
+                }
+            }
+        }
+    }
+
+    /// Encode spin-ordering `so` into genome.
+    pub fn encode(&self, so: &[u8]) -> Vec<bool> {
+        if self.is_binary() {
+            // the first bit fixed as spin-up
+            let so = if so[0] == 1 { so.to_vec() } else { self.reverse(so).unwrap() };
+            so[1..].iter().map(|&s| s == 1).collect()
+        } else {
+            let m = self.bits_per_site();
+            so.iter().flat_map(|&s| (0..m).rev().map(move |j| (s >> j) & 1 == 1)).collect()
+        }
+    }
+}
+
+/// Decode `bits` of a site into its code, with the most significant bit
+/// first.
+fn decode_bits(bits: &[bool]) -> usize {
+    bits.iter().fold(0, |acc, &b| acc * 2 + b as usize)
+}
+
+fn neg(v: [f64; 3]) -> [f64; 3] {
+    [-v[0], -v[1], -v[2]]
+}
+// b5d47c81 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_spin_states() -> Result<()> {
+    use rand::SeedableRng;
+
+    let spins = SpinStates::default();
+    assert!(spins.is_binary());
+    assert_eq!(spins.genome_size(4), 3);
+    assert_eq!(spins.decode(&[false, true, true]), vec![1, 0, 1, 1]);
+    assert_eq!(spins.encode(&[0, 1, 0, 0]), vec![false, true, true]);
+    assert_eq!(spins.normalize(&[0, 1, 0, 0]), vec![1, 0, 1, 1]);
+
+    // up, down and zero
+    let spins = SpinStates::new(vec![-1.0, 1.0, 0.0])?;
+    assert!(!spins.is_binary());
+    assert_eq!(spins.genome_size(4), 8);
+    let so = vec![2, 0, 1, 2];
+    assert_eq!(spins.decode(&spins.encode(&so)), so);
+    assert_eq!(spins.reverse(&so), Some(vec![2, 1, 0, 2]));
+    assert_eq!(spins.normalize(&so), vec![2, 1, 0, 2]);
+    // code 3 is invalid, and redrawn uniformly by repair
+    assert_eq!(spins.decode(&[true, true]), vec![3]);
+    assert!(spins.check_spin_ordering(&[0, 3]).is_err());
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut counts = [0; 3];
+    for _ in 0..3000 {
+        let mut genome = vec![true, true, false, true];
+        spins.repair(&mut genome, &mut rng);
+        let so = spins.decode(&genome);
+        assert_eq!(so[1], 1);
+        counts[so[0] as usize] += 1;
+    }
+    assert!(counts.iter().all(|&c| (900..1100).contains(&c)), "{:?}", counts);
+
+    // high-spin states only, which are not reversible
+    let spins = SpinStates::new(vec![1.0, 0.4])?;
+    assert_eq!(spins.reverse(&[0, 1]), None);
+    assert!(SpinStates::new(vec![1.0]).is_err());
+    assert!(SpinStates::new(vec![1.0, 1.0]).is_err());
+    let spins: SpinStates = serde_json::from_str("[-1.0, 1.0, 0.0]")?;
+    assert_eq!(spins.nstates(), 3);
+    assert!(serde_json::from_str::<SpinStates>("[1.0]").is_err());
+    assert!(spins.is_collinear());
+    assert_eq!(serde_json::to_string(&spins)?, "[-1.0,1.0,0.0]");
+
+    // non-collinear: along and perpendicular to the quantization axis
+    let spins: SpinStates = serde_json::from_str("[1.0, -1.0, [1.0, 0.0, 0.0], [-1.0, 0.0, 0.0]]")?;
+    assert!(!spins.is_collinear());
+    assert_eq!(spins.moment(0), [0.0, 0.0, 1.0]);
+    assert_eq!(spins.moment(2), [1.0, 0.0, 0.0]);
+    assert_eq!(spins.value(2), 0.0);
+    assert_eq!(spins.genome_size(3), 6);
+    assert_eq!(spins.reverse(&[0, 2, 3]), Some(vec![1, 3, 2]));
+
+    Ok(())
+}
+// test:1 ends here
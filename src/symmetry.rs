@@ -0,0 +1,223 @@
+// [[file:../magman.note::5a1c7e3b][5a1c7e3b]]
+use super::*;
+use crate::poscar::Poscar;
+use crate::spin::SpinStates;
+
+use std::collections::HashSet;
+use std::path::Path;
+// 5a1c7e3b ends here
+
+// [[file:../magman.note::c2f84d16][c2f84d16]]
+/// Tolerance in Angstrom for matching atom positions under symmetry operations.
+const SYMPREC: f64 = 1e-3;
+
+/// Symmetry operations of crystal represented as permutations of magnetic
+/// sites.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpinSymmetry {
+    permutations: Vec<Vec<usize>>,
+}
+
+impl SpinSymmetry {
+    /// Find symmetry operations of crystal in POSCAR file `path` that
+    /// permute magnetic sites in `sites`.
+    pub fn from_poscar_file<P: AsRef<Path>>(path: P, sites: &[usize]) -> Result<Self> {
+        let poscar = Poscar::from_file(path)?;
+        Self::from_poscar(&poscar, sites)
+    }
+
+    /// Find symmetry operations of crystal `poscar` that permute magnetic
+    /// sites in `sites` (atom indices in POSCAR order).
+    pub fn from_poscar(poscar: &Poscar, sites: &[usize]) -> Result<Self> {
+        if let Some(&i) = sites.iter().find(|&&i| i >= poscar.natoms()) {
+            bail!("magnetic site {} out of range of {} atoms", i + 1, poscar.natoms());
+        }
+
+        let mut found = HashSet::new();
+        let mut permutations = vec![];
+        for perm in find_atom_permutations(poscar) {
+            // restrict to operations mapping magnetic sites onto magnetic sites
+            let p: Option<Vec<usize>> = sites
+                .iter()
+                .map(|&i| sites.iter().position(|&j| j == perm[i]))
+                .collect();
+            if let Some(p) = p {
+                if found.insert(p.clone()) {
+                    permutations.push(p);
+                }
+            }
+        }
+        info!("Found {} symmetry operations for {} magnetic sites.", permutations.len(), sites.len());
+
+        Ok(Self { permutations })
+    }
+
+    /// Return the number of magnetic sites.
+    pub fn nsites(&self) -> usize {
+        self.permutations.first().map_or(0, |p| p.len())
+    }
+
+    /// Map spin-ordering `so` to the canonical representative of all its
+    /// symmetry-equivalent spin-orderings. Reversing all spins using
+    /// `spins` is also considered, so the representative of up/down
+    /// spin-orderings always has the first site spin-up. Without any
+    /// symmetry operation, `so` is returned as it is.
+    pub fn canonicalize(&self, so: &[u8], spins: &SpinStates) -> Result<Vec<u8>> {
+        if self.permutations.is_empty() {
+            return Ok(so.to_vec());
+        }
+        if so.len() != self.nsites() {
+            bail!("spin-ordering of {} sites for symmetry of {} magnetic sites", so.len(), self.nsites());
+        }
+
+        let mut candidates = vec![so.to_vec()];
+        candidates.extend(spins.reverse(so));
+        let mut best = vec![];
+        let mut new = vec![0; so.len()];
+        for p in self.permutations.iter() {
+            for c in candidates.iter() {
+                for (i, &j) in p.iter().enumerate() {
+                    new[j] = c[i];
+                }
+                if new > best {
+                    best.clone_from(&new);
+                }
+            }
+        }
+        Ok(best)
+    }
+}
+
+/// Find space group operations of crystal `poscar`, represented as
+/// permutations of all atoms.
+fn find_atom_permutations(poscar: &Poscar) -> Vec<Vec<usize>> {
+    let positions = &poscar.positions;
+    let symbols = &poscar.symbols;
+    if positions.is_empty() {
+        return vec![];
+    }
+
+    // use the rarest species for generating candidate translations
+    let iref = (0..symbols.len())
+        .min_by_key(|&i| symbols.iter().filter(|s| *s == &symbols[i]).count())
+        .unwrap();
+
+    let mut permutations = vec![];
+    for w in find_lattice_rotations(&poscar.lattice) {
+        let rotated: Vec<_> = positions.iter().map(|&x| rotate(&w, x)).collect();
+        for j in (0..symbols.len()).filter(|&j| symbols[j] == symbols[iref]) {
+            let t = [0, 1, 2].map(|k| positions[j][k] - rotated[iref][k]);
+            let perm: Option<Vec<usize>> = rotated
+                .iter()
+                .enumerate()
+                .map(|(i, &x)| {
+                    let y = [0, 1, 2].map(|k| x[k] + t[k]);
+                    (0..positions.len()).find(|&k| symbols[k] == symbols[i] && poscar.distance(y, positions[k]) < SYMPREC)
+                })
+                .collect();
+            if let Some(perm) = perm {
+                permutations.push(perm);
+            }
+        }
+    }
+
+    permutations
+}
+
+/// Find integer matrices rotating fractional coordinates that preserve the
+/// lattice metric. Only matrices with entries in {-1, 0, 1} are considered,
+/// which include all rotations of a Niggli-reduced cell, but may miss some
+/// of a skewed cell, such as a supercell built from non-reduced vectors.
+/// The missed operations only make fewer spin-orderings recognized as
+/// equivalent.
+fn find_lattice_rotations(lattice: &[[f64; 3]; 3]) -> Vec<[[i32; 3]; 3]> {
+    let mut metric = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            metric[i][j] = (0..3).map(|k| lattice[i][k] * lattice[j][k]).sum();
+        }
+    }
+    let tol = 1e-3 * (0..3).map(|i| metric[i][i]).fold(0.0, f64::max);
+
+    let mut rotations = vec![];
+    for code in 0..3usize.pow(9) {
+        let mut w = [[0i32; 3]; 3];
+        let mut c = code;
+        for x in w.iter_mut().flatten() {
+            *x = (c % 3) as i32 - 1;
+            c /= 3;
+        }
+        // W^T G W = G
+        let ok = (0..3).all(|i| {
+            (0..3).all(|j| {
+                let mut g = 0.0;
+                for k in 0..3 {
+                    for l in 0..3 {
+                        g += w[k][i] as f64 * metric[k][l] * w[l][j] as f64;
+                    }
+                }
+                (g - metric[i][j]).abs() < tol
+            })
+        });
+        if ok {
+            rotations.push(w);
+        }
+    }
+
+    rotations
+}
+
+fn rotate(w: &[[i32; 3]; 3], x: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| (0..3).map(|j| w[i][j] as f64 * x[j]).sum())
+}
+// c2f84d16 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_spin_symmetry() -> Result<()> {
+    let sym = SpinSymmetry::from_poscar_file("tests/files/template/POSCAR", &(0..12).collect::<Vec<_>>())?;
+    let spins = SpinStates::default();
+    assert_eq!(sym.nsites(), 12);
+    // 36 operations of R-3c in hexagonal setting, but all Fe atoms sit on
+    // the 3-fold axes
+    assert_eq!(sym.permutations.len(), 12);
+
+    // symmetry-equivalent spin-orderings, including reversed ones, have the
+    // same energy within 0.01 eV. All orderings in these calculations have
+    // the first site spin-up, and many of those with minority spin-up were
+    // trapped in different local minima: 32 of the 210 classes are not
+    // consistent, each with an ordering of minority spin-up.
+    let mut rdr = csv::Reader::from_path("tests/files/results.csv")?;
+    let mut classes: std::collections::HashMap<_, Vec<_>> = std::collections::HashMap::new();
+    for record in rdr.records() {
+        let record = record?;
+        let so = crate::magmom::parse_spin_key(&record[0])?;
+        let energy: f64 = record[1].parse()?;
+        let key = crate::magmom::spin_key(&sym.canonicalize(&so, &spins)?);
+        classes.entry(key).or_default().push((so, energy));
+    }
+    assert_eq!(classes.len(), 210);
+    let mut ninconsistent = 0;
+    for items in classes.values() {
+        let emin = items.iter().map(|x| x.1).fold(f64::INFINITY, f64::min);
+        let emax = items.iter().map(|x| x.1).fold(f64::NEG_INFINITY, f64::max);
+        if emax - emin > 1e-2 {
+            ninconsistent += 1;
+            assert!(items.iter().any(|(so, _)| so.iter().filter(|&&x| x == 1).count() < 6));
+        }
+    }
+    assert_eq!(ninconsistent, 32);
+
+    // the canonical form is idempotent and always starts with spin-up
+    let so = vec![0, 1, 1, 0, 0, 1, 0, 0, 1, 1, 0, 1];
+    let c = sym.canonicalize(&so, &spins)?;
+    assert_eq!(c[0], 1);
+    assert_eq!(sym.canonicalize(&c, &spins)?, c);
+    assert!(sym.canonicalize(&so[1..], &spins).is_err());
+    // no symmetry operation found
+    let sym = SpinSymmetry { permutations: vec![] };
+    assert_eq!(sym.canonicalize(&so, &spins)?, so);
+
+    Ok(())
+}
+// test:1 ends here
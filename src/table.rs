@@ -0,0 +1,243 @@
+// [[file:../magman.note::e1c95b28][e1c95b28]]
+use super::*;
+use crate::magmom::MagneticState;
+use crate::spin::SpinStates;
+
+use std::path::Path;
+// e1c95b28 ends here
+
+// [[file:../magman.note::39ad7f60][39ad7f60]]
+/// A row of magnetic states in tabular format, compatible with the
+/// `results.csv` table produced by magcalc.py.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Record {
+    /// Binary encoded key of spin-ordering, which is also the name of job
+    /// directory.
+    pub directory: String,
+    pub energy: f64,
+    /// Spin-ordering in `+-` sequence, with `0` for nonmagnetic state.
+    pub seqs: String,
+    /// Net magnetization in number of spins: |n_up - n_down|.
+    pub net_mag: usize,
+    /// Energy relative to the lowest one.
+    #[serde(default)]
+    pub relative_energy: Option<f64>,
+    /// Path to VASP job directory.
+    #[serde(default)]
+    pub job_directory: Option<String>,
+    /// Unix timestamp in seconds when evaluated.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+impl Record {
+    fn from_state(ms: &MagneticState, e0: f64, working_directory: &Path, spins: &SpinStates) -> Self {
+        let key = ms.spin_key();
+        let values: Vec<_> = ms.spin_ordering.iter().map(|&s| spins.value(s)).collect();
+        let nup = values.iter().filter(|&&v| v > 0.0).count();
+        let ndown = values.iter().filter(|&&v| v < 0.0).count();
+        let seqs = values
+            .iter()
+            .map(|&v| match v.partial_cmp(&0.0) {
+                Some(std::cmp::Ordering::Greater) => '+',
+                Some(std::cmp::Ordering::Less) => '-',
+                _ => '0',
+            })
+            .collect();
+        let adir = working_directory.join(&key);
+        Self {
+            seqs,
+            energy: ms.energy,
+            net_mag: nup.max(ndown) - nup.min(ndown),
+            relative_energy: Some(ms.energy - e0),
+            job_directory: adir.is_dir().then(|| adir.display().to_string()),
+            timestamp: ms.timestamp,
+            directory: key,
+        }
+    }
+}
+
+impl Record {
+    /// Return spin-ordering from the key in `directory` column.
+    pub fn spin_ordering(&self) -> Result<Vec<u8>> {
+        crate::magmom::parse_spin_key(&self.directory)
+    }
+
+    /// Convert into magnetic state.
+    pub fn to_state(&self) -> Result<MagneticState> {
+        let so = self.spin_ordering()?;
+        let mut ms = MagneticState::new(&so, self.energy);
+        ms.timestamp = self.timestamp;
+        Ok(ms)
+    }
+}
+
+/// Read records from csv table in `path`.
+pub fn read_csv<P: AsRef<Path>>(path: P) -> Result<Vec<Record>> {
+    let path = path.as_ref();
+    let mut rdr = csv::Reader::from_path(path).with_context(|| format!("open csv file {}", path.display()))?;
+    let mut records = vec![];
+    for result in rdr.deserialize() {
+        let record: Record = result.with_context(|| format!("parse csv file {}", path.display()))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Convert magnetic states in `spins` into records sorted by energy, with
+/// job directories located in `working_directory`.
+pub fn records_from_states(states: &[MagneticState], working_directory: &Path, spins: &SpinStates) -> Vec<Record> {
+    let e0 = states.iter().map(|ms| ms.energy).fold(f64::INFINITY, f64::min);
+    let mut records: Vec<_> = states
+        .iter()
+        .map(|ms| Record::from_state(ms, e0, working_directory, spins))
+        .collect();
+    records.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+    records
+}
+
+/// Format `records` as csv table.
+pub fn format_csv(records: &[Record]) -> Result<String> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for record in records {
+        wtr.serialize(record)?;
+    }
+    let txt = String::from_utf8(wtr.into_inner()?)?;
+    Ok(txt)
+}
+
+/// Format `records` as json.
+pub fn format_json(records: &[Record]) -> Result<String> {
+    let txt = serde_json::to_string_pretty(records)?;
+    Ok(txt)
+}
+
+/// Export all magnetic states in database in `format` ("csv" or "json").
+pub fn export_db(format: &str) -> Result<String> {
+    let states = MagneticState::list_all()?;
+    let vasp = &crate::config::MAGMAN_CONFIG.vasp;
+    let records = records_from_states(&states, vasp.working_directory(), vasp.spin_states());
+    match format {
+        "csv" => format_csv(&records),
+        "json" => format_json(&records),
+        _ => bail!("unsupported export format: {format}"),
+    }
+}
+// 39ad7f60 ends here
+
+// [[file:../magman.note::5c8e21b4][5c8e21b4]]
+/// Import magnetic states from csv table file or from job directories in
+/// `path` into database. An existing item whose energy differs from the
+/// imported one by more than `tolerance` is reported as conflict, and will
+/// be overwritten only if `force` is true.
+pub fn import(path: &Path, force: bool, tolerance: f64) -> Result<()> {
+    use crate::magmom::EvaluateMagneticState;
+
+    let config = &crate::config::MAGMAN_CONFIG;
+    let vasp = &config.vasp;
+    let n = crate::config::genome_length()?;
+
+    let states = if path.is_dir() {
+        vasp.collect_results(path)?
+    } else {
+        read_csv(path)?.iter().map(|r| r.to_state()).collect::<Result<Vec<_>>>()?
+    };
+    println!("Found {} items in {}", states.len(), path.display());
+
+    let mut nimported = 0;
+    let mut nduplicated = 0;
+    let mut ninvalid = 0;
+    let mut conflicts = vec![];
+    for mut ms in states {
+        if ms.spin_ordering.len() != n {
+            warn!("ignored {}: key length differs from genome length {}", ms.spin_key(), n);
+            ninvalid += 1;
+            continue;
+        }
+        if let Err(e) = vasp.spin_states().check_spin_ordering(&ms.spin_ordering) {
+            warn!("ignored {}: {}", ms.spin_key(), e);
+            ninvalid += 1;
+            continue;
+        }
+        // symmetry-equivalent spin-orderings are saved using the same key
+        let so = vasp.canonicalize(&ms.spin_ordering);
+        if so != ms.spin_ordering {
+            debug!("{} is equivalent to {}", ms.spin_key(), crate::magmom::spin_key(&so));
+            ms.spin_ordering = so;
+            ms.converged_ordering = None;
+            ms.magnetic_moments = None;
+            ms.magnetic_moment_vectors = None;
+        }
+        let key = ms.spin_key();
+        if let Some(old) = MagneticState::load(&key)? {
+            if (old.energy - ms.energy).abs() <= tolerance {
+                nduplicated += 1;
+                continue;
+            }
+            conflicts.push((key, old.energy, ms.energy));
+            if !force {
+                continue;
+            }
+        }
+        ms.save()?;
+        nimported += 1;
+    }
+
+    for (key, old, new) in conflicts.iter() {
+        println!("conflict {}: {:-12.5} in db vs {:-12.5} imported", key, old, new);
+    }
+    println!(
+        "{} imported, {} duplicated, {} conflicts{}, {} invalid.",
+        nimported,
+        nduplicated,
+        conflicts.len(),
+        if force { " overwritten" } else { " skipped" },
+        ninvalid
+    );
+
+    Ok(())
+}
+// 5c8e21b4 ends here
+
+// [[file:../magman.note::*test][test:1]]
+#[test]
+fn test_export_records() -> Result<()> {
+    let states = vec![
+        MagneticState::new(&[1, 0, 0, 1], -10.5),
+        MagneticState::new(&[1, 1, 1, 0], -12.0),
+    ];
+    let records = records_from_states(&states, "jobs".as_ref(), &SpinStates::default());
+    assert_eq!(records[0].directory, "1110");
+    assert_eq!(records[0].seqs, "+++-");
+    assert_eq!(records[0].net_mag, 2);
+    assert_eq!(records[1].net_mag, 0);
+    assert_eq!(records[1].relative_energy, Some(1.5));
+
+    let txt = format_csv(&records)?;
+    let mut lines = txt.lines();
+    assert_eq!(lines.next(), Some("directory,energy,seqs,net_mag,relative_energy,job_directory,timestamp"));
+    assert!(lines.next().unwrap().starts_with("1110,-12.0,+++-,2,0.0,,"));
+
+    // compatible with results.csv
+    let records = read_csv("tests/files/results.csv")?;
+    assert_eq!(records.len(), 2048);
+    let record = &records[0];
+    assert_eq!(record.directory, "100110011001");
+    assert_eq!(record.timestamp, None);
+    let ms = record.to_state()?;
+    assert_eq!(ms.spin_ordering.len(), 12);
+    assert_eq!(ms.energy, -205.30249);
+
+    assert!(crate::magmom::parse_spin_key("10-1").is_err());
+
+    // up, down and zero
+    let spins = SpinStates::new(vec![-1.0, 1.0, 0.0])?;
+    let states = vec![MagneticState::new(&[1, 2, 0, 1], -10.5)];
+    let records = records_from_states(&states, "jobs".as_ref(), &spins);
+    assert_eq!(records[0].directory, "1201");
+    assert_eq!(records[0].seqs, "+0-+");
+    assert_eq!(records[0].net_mag, 1);
+
+    Ok(())
+}
+// test:1 ends here
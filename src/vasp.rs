@@ -1,65 +1,28 @@
 // [[file:../magman.note::6445afac][6445afac]]
-use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use super::*;
+use crate::incar::Incar;
+use crate::magmom::EvaluateMagneticState;
+use crate::symmetry::SpinSymmetry;
 use crate::MAG_DB_CONNECTION;
 
 use gosh::db::prelude::*;
 // 6445afac ends here
 
 lazy_static! {
-    static ref CSV_DATA: HashMap<String, Record> = {
-        let filename = "tests/files/results.csv";
-        read_data(filename).expect("magresult")
+    /// Symmetry operations on magnetic sites of the template structure, or
+    /// None if not available.
+    static ref SPIN_SYMMETRY: Option<SpinSymmetry> = {
+        let config = &crate::config::MAGMAN_CONFIG;
+        let poscar = config.vasp.template_directory.join("POSCAR");
+        crate::config::magnetic_sites()
+            .and_then(|sites| SpinSymmetry::from_poscar_file(&poscar, &sites.sites))
+            .map_err(|e| error!("Failed to find symmetry from {}, which is ignored: {:?}", poscar.display(), e))
+            .ok()
     };
 }
 
-// for test
-pub struct CsvEvaluator;
-impl crate::magmom::EvaluateMagneticState for CsvEvaluator {
-    fn evaluate_new(&self, so: &[bool]) -> Result<crate::magmom::MagneticState> {
-        let key = crate::magmom::binary_key(so);
-        let ms = if let Some(record) = &CSV_DATA.get(&key) {
-            let energy = record.energy;
-            info!("item {:} energy = {:-18.6}", key, energy);
-            crate::magmom::MagneticState::new(so, energy)
-        } else {
-            bail!("Record not found: {}", crate::magmom::binary_key(so))
-        };
-
-        Ok(ms)
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Record {
-    directory: String,
-    energy: f64,
-    seqs: String,
-    net_mag: usize,
-}
-
-// read data records from an external csv file
-fn read_data(filename: &str) -> Result<HashMap<String, Record>> {
-    let mut rdr = csv::Reader::from_path(filename)?;
-
-    let mut data = HashMap::new();
-    for result in rdr.deserialize() {
-        let record: Record = result?;
-        data.insert(record.directory.clone(), record);
-    }
-
-    Ok(data)
-}
-
-#[test]
-#[ignore]
-fn test_read_data() {
-    let filename = "tests/files/results.csv";
-    let x = read_data(filename).expect("magresult");
-}
-
 // [[file:../magman.note::f6ae3a4b][f6ae3a4b]]
 /// VASP related data
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
@@ -70,21 +33,98 @@ pub struct Vasp {
     /// Initial value of MAGMOM for magnetic atom.
     initial_magmom_value: f64,
 
+    /// Spin states of magnetic sites, in unit of `initial_magmom_value`.
+    /// Defaults to `[-1.0, 1.0]` for spin-down and spin-up. Spin states in
+    /// 3-vectors require non-collinear calculations.
+    #[serde(default)]
+    spin_states: crate::spin::SpinStates,
+
+    /// Spin quantization axis written as SAXIS for non-collinear
+    /// calculations. When set, or when any spin state is a 3-vector, MAGMOM
+    /// is written as three components for each magnetic site. Defaults to
+    /// `[0.0, 0.0, 1.0]` in non-collinear calculations.
+    #[serde(default)]
+    saxis: Option<[f64; 3]>,
+
     /// VASP template directory for calculations of different spin-orderings.
     template_directory: PathBuf,
 
     /// Working directory for all VASP calculations.
     working_directory: PathBuf,
 
-    /// The placeholder string in INCAR to be replaced by each spin-ordering.
+    /// The placeholder string for MAGMOM in template INCAR. MAGMOM is
+    /// written for all atoms in POSCAR for each spin-ordering.
     placeholder_text: String,
+
+    /// Magnetic sites in template POSCAR, given as element symbols such as
+    /// `["Fe"]`, or as atom indices counting from 1 such as `[1, 2, 5]`.
+    /// This is required unless `genome_length` is set in config, in which
+    /// case the leading `genome_length` atoms are magnetic sites.
+    #[serde(default)]
+    magnetic_sites: Option<crate::poscar::SiteSelection>,
+
+    /// Skip symmetry-equivalent spin-orderings using the symmetry of
+    /// template POSCAR. This is ignored in non-collinear calculations, as
+    /// spin directions are coupled to the lattice by spin-orbit coupling.
+    /// Some operations may be missed for a cell not Niggli-reduced.
+    #[serde(default)]
+    use_symmetry: bool,
+
+    /// Magnetic moments smaller than this value in magnitude are
+    /// considered as spin flips.
+    #[serde(default = "default_moment_threshold")]
+    moment_threshold: f64,
+
+    /// Initial MAGMOM values overriding `initial_magmom_value` for magnetic
+    /// species, such as `Fe = 5.0`, or for magnetic sites by atom index
+    /// counting from 1, such as `"7" = 3.0`. Site indices take precedence
+    /// over species.
+    #[serde(default)]
+    initial_magmom_values: std::collections::BTreeMap<String, f64>,
+
+    /// Submit VASP jobs to a batch job scheduler instead of running
+    /// `cmdline` directly.
+    scheduler: Option<crate::scheduler::Scheduler>,
+
+    /// Rerun failed VASP jobs with fixed INCAR. Failed jobs are not rerun
+    /// if it is not set.
+    recovery: Option<crate::recovery::Recovery>,
+
+    /// Calculate each spin-ordering using a cheap screening template first,
+    /// and only refine the promising ones using the production template.
+    screening: Option<crate::screening::Screening>,
+}
+
+fn default_moment_threshold() -> f64 {
+    0.5
 }
 
 /// VASP Evaluator
 impl crate::magmom::EvaluateMagneticState for Vasp {
-    fn evaluate_new(&self, so: &[bool]) -> Result<crate::magmom::MagneticState> {
+    fn canonicalize(&self, so: &[u8]) -> Vec<u8> {
+        if self.use_symmetry && self.noncollinear_axis().is_none() {
+            if let Some(symmetry) = SPIN_SYMMETRY.as_ref() {
+                match symmetry.canonicalize(so, &self.spin_states) {
+                    Ok(so) => return so,
+                    Err(e) => warn!("symmetry is ignored: {:?}", e),
+                }
+            }
+        }
+        self.spin_states.normalize(so)
+    }
+
+    fn evaluate_new(&self, so: &[u8]) -> Result<crate::magmom::MagneticState> {
+        if let Some(screen) = self.screening_stage() {
+            let mut ms = screen.evaluate_new(so)?;
+            ms.screening_energy = Some(ms.energy);
+            ms.screening_only = true;
+            return Ok(ms);
+        }
         let energy = self.calculate_new(so)?;
-        let ms = crate::magmom::MagneticState::new(so, energy);
+        let adir = self.job_directory(so);
+        let mut ms = crate::magmom::MagneticState::new(so, energy);
+        self.read_magnetic_moments(&mut ms, &adir);
+        ms.attempts = crate::recovery::read_attempts(&adir);
         Ok(ms)
     }
 }
@@ -95,33 +135,162 @@ impl Default for Vasp {
             cmdline: "run-vasp.sh".into(),
             template_directory: "template".into(),
             initial_magmom_value: 5.0,
+            initial_magmom_values: Default::default(),
+            spin_states: Default::default(),
+            saxis: None,
             working_directory: "jobs".into(),
             placeholder_text: "XXXXX".into(),
+            magnetic_sites: None,
+            use_symmetry: false,
+            moment_threshold: default_moment_threshold(),
+            scheduler: None,
+            recovery: None,
+            screening: None,
         }
     }
 }
 
 impl Vasp {
+    /// VASP template directory.
+    pub(crate) fn template_directory(&self) -> &Path {
+        &self.template_directory
+    }
+
+    /// Working directory for all VASP calculations.
+    pub(crate) fn working_directory(&self) -> &Path {
+        &self.working_directory
+    }
+
+    /// Spin states of magnetic sites.
+    pub(crate) fn spin_states(&self) -> &crate::spin::SpinStates {
+        &self.spin_states
+    }
+
+    /// Two-stage screening settings.
+    pub(crate) fn screening(&self) -> Option<&crate::screening::Screening> {
+        self.screening.as_ref()
+    }
+
+    /// Return VASP settings for screening calculations, using screening
+    /// template and working directory.
+    fn screening_stage(&self) -> Option<Self> {
+        let screening = self.screening.as_ref()?;
+        Some(Self {
+            template_directory: screening.template_directory().to_owned(),
+            working_directory: screening.working_directory().to_owned(),
+            screening: None,
+            ..self.clone()
+        })
+    }
+
+    /// Recalculate screened spin-ordering `so` using production template,
+    /// keeping its screening energy. The refined item is saved in database.
+    pub(crate) fn refine(&self, so: &[u8]) -> Result<crate::magmom::MagneticState> {
+        let so = &self.canonicalize(so);
+        let key = crate::magmom::spin_key(so);
+        let screened = crate::magmom::MagneticState::load(&key)?.with_context(|| format!("{key} not screened"))?;
+        if !screened.screening_only {
+            return Ok(screened);
+        }
+        let energy = self.calculate_new(so)?;
+        let adir = self.job_directory(so);
+        let mut ms = crate::magmom::MagneticState::new(so, energy);
+        self.read_magnetic_moments(&mut ms, &adir);
+        ms.attempts = crate::recovery::read_attempts(&adir);
+        ms.screening_energy = Some(screened.screened_energy());
+        ms.put_into_collection(&MAG_DB_CONNECTION, &key)
+            .with_context(|| format!("put {key} into db"))?;
+        Ok(ms)
+    }
+
+    /// Return job directories of spin-ordering `so`, including the one for
+    /// screening calculation if enabled.
+    pub(crate) fn job_directories(&self, so: &[u8]) -> Vec<PathBuf> {
+        let mut dirs = vec![self.job_directory(so)];
+        dirs.extend(self.screening_stage().map(|vasp| vasp.job_directory(so)));
+        dirs
+    }
+
+    /// Return the spin quantization axis if calculations are non-collinear.
+    pub(crate) fn noncollinear_axis(&self) -> Option<[f64; 3]> {
+        if self.saxis.is_some() || !self.spin_states.is_collinear() {
+            Some(self.saxis.unwrap_or([0.0, 0.0, 1.0]))
+        } else {
+            None
+        }
+    }
+
+    /// Find magnetic sites in template POSCAR. The first `genome_length`
+    /// atoms are treated as magnetic sites if `magnetic_sites` is not set.
+    pub(crate) fn find_magnetic_sites(&self, genome_length: Option<usize>) -> Result<crate::poscar::MagneticSites> {
+        let poscar = crate::poscar::Poscar::from_file(self.template_directory.join("POSCAR"))?;
+        let sites = match (&self.magnetic_sites, genome_length) {
+            (Some(selection), _) => poscar.select_sites(selection)?,
+            (None, Some(n)) => poscar.leading_sites(n)?,
+            (None, None) => bail!("magnetic_sites, such as [\"Fe\"] or [1, 2, 5], or genome_length should be set in config"),
+        };
+        if let Some(n) = genome_length {
+            if n != sites.sites.len() {
+                bail!("genome_length {} differs from {} magnetic sites in POSCAR", n, sites.sites.len());
+            }
+        }
+        for key in self.initial_magmom_values.keys() {
+            let found = sites.symbols.contains(key) || sites.sites.iter().any(|i| (i + 1).to_string() == *key);
+            if !found {
+                warn!("initial MAGMOM value for {} matches no magnetic site", key);
+            }
+        }
+
+        Ok(sites)
+    }
+
+    /// List job directories in working directory, and also in screening
+    /// working directory if enabled, together with their finished state.
+    pub(crate) fn list_job_directories(&self) -> Result<Vec<(PathBuf, bool)>> {
+        let mut jobs = vec![];
+        let mut wdirs = vec![self.working_directory.as_path()];
+        wdirs.extend(self.screening.as_ref().map(|s| s.working_directory()));
+        for wdir in wdirs.into_iter().filter(|d| d.is_dir()) {
+            for entry in std::fs::read_dir(wdir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    let done = self.already_done(&path);
+                    jobs.push((path, done));
+                }
+            }
+        }
+        jobs.sort();
+
+        Ok(jobs)
+    }
+
     /// Call VASP to calculate energy with spin-ordering of `so`.
-    pub(crate) fn calculate_new(&self, so: &[bool]) -> Result<f64> {
+    pub(crate) fn calculate_new(&self, so: &[u8]) -> Result<f64> {
         // use gut::cli::duct::cmd;
-        use std::process::Command;
 
         // fix cmdline relative path issue
         let cmdline: &Path = self.cmdline.as_ref();
         let cmdline = cmdline.canonicalize()?;
+        // MAGMOM should be consistent with the job directory
+        let so = &self.canonicalize(so);
         let adir = self.job_directory(so);
         debug!("Evaluate job in {adir:?}");
         if !self.already_done(&adir) {
             self.prepare_vasp_inputs(so)?;
-            debug!("calculate new job {adir:?} using {cmdline:?}");
-            let o = Command::new(&cmdline)
-                .current_dir(&adir)
-                .output()
-                .with_context(|| format!("run {cmdline:?}"))?;
-            if !o.status.success() {
-                bail!("vasp failed with output: {o:?}");
+            self.run_job(&cmdline, &adir)?;
+        }
+        // energy of abnormally finished job can not be trusted
+        let mut attempts = crate::recovery::read_attempts(&adir);
+        while let Err(mut failure) = crate::failure::inspect_job(&adir) {
+            let recovered = match &self.recovery {
+                Some(recovery) => recovery.apply(&adir, &failure, &mut attempts)?,
+                None => false,
+            };
+            if !recovered {
+                failure.attempts = attempts;
+                return Err(failure.into());
             }
+            self.run_job(&cmdline, &adir)?;
         }
         let oszicar = adir.join("OSZICAR");
         let energy = get_energy_from_oszicar(oszicar).with_context(|| format!("get energy for {adir:?}"))?;
@@ -129,9 +298,29 @@ impl Vasp {
         Ok(energy)
     }
 
-    /// Collect items from disk files.
-    pub(crate) fn collect_results(&self) -> Result<Vec<crate::magmom::MagneticState>> {
-        let dir = &self.working_directory;
+    /// Run VASP job in directory `adir` using `cmdline`.
+    fn run_job(&self, cmdline: &Path, adir: &Path) -> Result<()> {
+        use std::process::Command;
+
+        debug!("calculate new job {adir:?} using {cmdline:?}");
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.run(cmdline, adir).with_context(|| format!("run {cmdline:?} using scheduler"))?;
+        } else {
+            let o = Command::new(cmdline)
+                .current_dir(adir)
+                .output()
+                .with_context(|| format!("run {cmdline:?}"))?;
+            // failed job with output files is inspected by caller
+            if !o.status.success() && crate::failure::inspect_job(adir).is_ok() {
+                bail!("vasp failed with output: {o:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collect items from job directories in `dir`.
+    pub(crate) fn collect_results(&self, dir: &Path) -> Result<Vec<crate::magmom::MagneticState>> {
         let mut list = vec![];
         if dir.is_dir() {
             for entry in std::fs::read_dir(dir)? {
@@ -139,19 +328,25 @@ impl Vasp {
                 let path = entry.path();
                 if path.is_dir() {
                     let key = path.file_name().unwrap().to_str().unwrap();
+                    let so = match crate::magmom::parse_spin_key(key) {
+                        Ok(so) if self.spin_states.check_spin_ordering(&so).is_ok() => so,
+                        _ => {
+                            warn!("ignored directory {}", path.display());
+                            continue;
+                        }
+                    };
+                    if let Err(failure) = crate::failure::inspect_job(&path) {
+                        warn!("ignored job {}: {}", key, failure);
+                        continue;
+                    }
                     let oszicar = path.join("OSZICAR");
                     match get_energy_from_oszicar(oszicar) {
                         Ok(energy) => {
                             println!("job {}, energy = {}", key, energy);
-                            let so: Vec<bool> = key
-                                .chars()
-                                .map(|b| match b {
-                                    '1' => true,
-                                    '0' => false,
-                                    _ => panic!("bad key: {}", key),
-                                })
-                                .collect();
-                            list.push(crate::magmom::MagneticState::new(&so, energy));
+                            let mut ms = crate::magmom::MagneticState::new(&so, energy);
+                            self.read_magnetic_moments(&mut ms, &path);
+                            ms.attempts = crate::recovery::read_attempts(&path);
+                            list.push(ms);
                         }
                         Err(e) => {
                             error!("{}", e);
@@ -164,7 +359,35 @@ impl Vasp {
         Ok(list)
     }
 
-    /// Inspecting VASP files in disk.
+    /// Read converged magnetic moments of `ms` from OUTCAR in job directory
+    /// `adir`.
+    fn read_magnetic_moments(&self, ms: &mut crate::magmom::MagneticState, adir: &Path) {
+        let outcar = adir.join("OUTCAR");
+        let threshold = self.moment_threshold;
+        let read = self.find_magnetic_sites(Some(ms.spin_ordering.len())).and_then(|sites| {
+            let magmoms: Vec<_> = (0..sites.sites.len()).map(|k| self.initial_magmom_value(k, &sites)).collect();
+            if self.noncollinear_axis().is_some() {
+                let m = get_magnetic_moment_vectors_from_outcar(&outcar)?;
+                ms.set_magnetic_moment_vectors(&m, &sites.sites, &magmoms, threshold, &self.spin_states)
+            } else {
+                let m = get_magnetic_moments_from_outcar(&outcar)?;
+                ms.set_magnetic_moments(&m, &sites.sites, &magmoms, threshold, &self.spin_states)
+            }
+        });
+        match read {
+            Ok(_) => {
+                if ms.spin_flipped {
+                    warn!("job {} relaxed into a different spin-ordering.", adir.display());
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read magnetic moments from {}: {:?}", outcar.display(), e);
+            }
+        }
+    }
+
+    /// Inspecting VASP files in disk. A job terminated without error
+    /// message, such as killed by job scheduler, is not done.
     fn already_done(&self, wdir: &Path) -> bool {
         let incar = wdir.join("INCAR");
         let oszicar = wdir.join("OSZICAR");
@@ -175,7 +398,8 @@ impl Vasp {
                 if let Ok(time2) = oszicar.metadata().and_then(|m| m.modified()) {
                     if let Ok(time1) = incar.metadata().and_then(|m| m.modified()) {
                         if time2 >= time1 {
-                            return true;
+                            use crate::failure::FailureKind;
+                            return !matches!(crate::failure::inspect_job(wdir), Err(f) if f.status == FailureKind::Unfinished);
                         }
                     }
                 }
@@ -185,53 +409,93 @@ impl Vasp {
         false
     }
 
-    /// Initial magnetic moment value without considering of spin ordering.
-    fn format_as_vasp_tag(&self, so: &[bool]) -> String {
-        let ss: Vec<_> = so
-            .iter()
-            .map(|&spin_up| {
-                let v = if spin_up { 1.0 } else { -1.0 } * self.initial_magmom_value;
-                format!("{:4.1}", v)
-            })
-            .collect();
+    /// Initial MAGMOM magnitude of the `k`-th magnetic site in `sites`.
+    fn initial_magmom_value(&self, k: usize, sites: &crate::poscar::MagneticSites) -> f64 {
+        let index = (sites.sites[k] + 1).to_string();
+        self.initial_magmom_values
+            .get(&index)
+            .or_else(|| self.initial_magmom_values.get(&sites.symbols[k]))
+            .copied()
+            .unwrap_or(self.initial_magmom_value)
+    }
+
+    /// Initial magnetic moment values of all atoms in POSCAR order, with
+    /// magnetic `sites` in spin-ordering `so`. Nonmagnetic atoms in a row
+    /// are written as `n*0.0`. In non-collinear calculations, each atom has
+    /// three components in the SAXIS frame.
+    fn format_as_vasp_tag(&self, so: &[u8], sites: &crate::poscar::MagneticSites) -> String {
+        let ncomponents = if self.noncollinear_axis().is_some() { 3 } else { 1 };
+        let mut ss = vec![];
+        let mut nzeros = 0;
+        for i in 0..sites.natoms {
+            let k = match sites.sites.iter().position(|&j| j == i) {
+                Some(k) => k,
+                None => {
+                    nzeros += ncomponents;
+                    continue;
+                }
+            };
+            let (s, magmom) = (so[k], self.initial_magmom_value(k, sites));
+            if nzeros > 0 {
+                ss.push(format_zeros(nzeros));
+                nzeros = 0;
+            }
+            if ncomponents == 3 {
+                let v = self.spin_states.moment(s).map(|x| format!("{:4.1}", x * magmom));
+                ss.push(v.join(" "));
+            } else {
+                let v = self.spin_states.value(s) * magmom;
+                ss.push(format!("{:4.1}", v));
+            }
+        }
+        if nzeros > 0 {
+            ss.push(format_zeros(nzeros));
+        }
         ss.join(" ")
     }
 
-    /// VASP job directory in spin-ordering `so`.
-    fn job_directory(&self, so: &[bool]) -> PathBuf {
-        self.working_directory.join(&crate::magmom::binary_key(so))
+    /// VASP job directory in spin-ordering `so`. Symmetry-equivalent
+    /// spin-orderings share the same job directory.
+    fn job_directory(&self, so: &[u8]) -> PathBuf {
+        self.working_directory.join(crate::magmom::spin_key(&self.canonicalize(so)))
     }
 
-    /// Prepare VASP input files in working directory.
-    fn prepare_vasp_inputs(&self, so: &[bool]) -> Result<()> {
-        use std::fs::File;
-        use std::io::{BufRead, BufReader};
-
-        let incar = &self.template_directory.join("INCAR");
-        let tag = self.placeholder_text.to_uppercase();
-
-        // replace MAGMOM tag
-        let mut new_lines = vec![];
-        let mut replaced = false;
-        for line in
-            BufReader::new(File::open(incar).with_context(|| format!("Failed to open VASP INCAR: {}", incar.display()))?).lines()
-        {
-            let mut line = line?;
-            let line_up = line.to_uppercase();
-            if line_up.contains("MAGMOM") {
-                if line_up.contains(&tag) {
-                    let new_tag = self.format_as_vasp_tag(so);
-                    line = line_up.replace(&tag, &new_tag);
-                    replaced = true;
-                }
+    /// Return INCAR from template for spin-ordering `so` on magnetic
+    /// `sites`, with MAGMOM and spin-polarization tags set.
+    fn prepare_incar(&self, so: &[u8], sites: &crate::poscar::MagneticSites) -> Result<Incar> {
+        let path = self.template_directory.join("INCAR");
+        let mut incar = Incar::from_file(&path)?;
+        for (tag, values) in incar.duplicate_tags() {
+            warn!("{} is set {} times in {}: {:?}", tag, values.len(), path.display(), values);
+        }
+        if let Some((tag, values)) = incar.conflicting_tags().first() {
+            bail!("{} is set to conflicting values in {}: {:?}", tag, path.display(), values);
+        }
+
+        match incar.get("MAGMOM") {
+            Some(v) if !v.to_uppercase().contains(&self.placeholder_text.to_uppercase()) => {
+                warn!("MAGMOM in {} without placeholder {} is overwritten.", path.display(), self.placeholder_text);
             }
-            new_lines.push(line);
+            _ => {}
         }
-        if !replaced {
-            eprintln!("Please fill MAGMOM line in INCAR with {} for templating.", tag);
-            bail!("placeholder for setting MAGMOM is not found!");
+        incar.set("MAGMOM", &self.format_as_vasp_tag(so, sites));
+        if let Some(saxis) = self.noncollinear_axis() {
+            let saxis = saxis.map(|x| x.to_string()).join(" ");
+            incar.set("LNONCOLLINEAR", ".TRUE.");
+            incar.set("SAXIS", &saxis);
+        } else if incar.get("ISPIN") != Some("2") {
+            warn!("ISPIN in {} is set to 2 for spin-polarized calculation.", path.display());
+            incar.set("ISPIN", "2");
         }
 
+        Ok(incar)
+    }
+
+    /// Prepare VASP input files in working directory.
+    fn prepare_vasp_inputs(&self, so: &[u8]) -> Result<()> {
+        let sites = self.find_magnetic_sites(Some(so.len()))?;
+        let incar = self.prepare_incar(so, &sites)?;
+
         // prepare vasp input files
         let poscar = self.template_directory.join("POSCAR");
         let potcar = self.template_directory.join("POTCAR");
@@ -239,12 +503,14 @@ impl Vasp {
 
         let adir = self.job_directory(so);
         std::fs::create_dir_all(&adir).with_context(|| format!("Failed to create VASP working directory: {}", adir.display()))?;
+        // recovery attempts are for the previous inputs
+        crate::recovery::reset_attempts(&adir)?;
 
         let new_incar = &adir.join("INCAR");
         let new_poscar = &adir.join("POSCAR");
         let new_potcar = &adir.join("POTCAR");
         let new_kpoints = &adir.join("KPOINTS");
-        gut::fs::write_to_file(new_incar, &new_lines.join("\n"))
+        gut::fs::write_to_file(new_incar, &incar.to_string())
             .with_context(|| format!("Failed to write new INCAR file: {}", new_incar.display()))?;
 
         // use linux symbolic link to reduce disk usage
@@ -268,6 +534,147 @@ impl Vasp {
     }
 }
 
+// [[file:../magman.note::a2f6c93d][a2f6c93d]]
+impl Vasp {
+    /// Check files in template directory and related settings, recording
+    /// all problems found into `report`.
+    pub(crate) fn check_template(&self, genome_length: Option<usize>, report: &mut crate::check::Report) {
+        let tdir = &self.template_directory;
+        if !tdir.is_dir() {
+            report.error("template", format!("template directory {} not found", tdir.display()));
+            return;
+        }
+        for f in ["INCAR", "POSCAR", "POTCAR", "KPOINTS"] {
+            if !tdir.join(f).is_file() {
+                report.error(f, format!("{} not found in {}", f, tdir.display()));
+            }
+        }
+
+        self.check_cmdline(report);
+        let poscar = crate::poscar::Poscar::from_file(tdir.join("POSCAR"));
+        match &poscar {
+            Ok(poscar) => check_potcar(poscar, &tdir.join("POTCAR"), report),
+            Err(e) => report.error("POSCAR", format!("{:#}", e)),
+        }
+        let sites = match self.find_magnetic_sites(genome_length) {
+            Ok(sites) => Some(sites),
+            Err(e) => {
+                if poscar.is_ok() {
+                    report.error("sites", format!("{:#}", e));
+                }
+                None
+            }
+        };
+        if let Ok(incar) = Incar::from_file(tdir.join("INCAR")) {
+            self.check_incar(&incar, sites.as_ref(), report);
+        }
+        if let Some(screen) = self.screening_stage() {
+            let mut screen_report = crate::check::Report::default();
+            screen.check_template(genome_length, &mut screen_report);
+            for p in screen_report.problems.into_iter().filter(|p| p.item != "cmdline" && p.item != "scheduler") {
+                report.problems.push(crate::check::Problem {
+                    item: format!("screen/{}", p.item),
+                    ..p
+                });
+            }
+        }
+    }
+
+    fn check_cmdline(&self, report: &mut crate::check::Report) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let cmdline: &Path = self.cmdline.as_ref();
+        match cmdline.canonicalize() {
+            Ok(path) => {
+                let executable = path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+                if !executable.unwrap_or(false) {
+                    report.error("cmdline", format!("{} is not executable", path.display()));
+                }
+            }
+            Err(e) => report.error("cmdline", format!("{}: {}", cmdline.display(), e)),
+        }
+        if let Some(scheduler) = &self.scheduler {
+            let script = scheduler.script_template();
+            if !script.is_file() {
+                report.error("scheduler", format!("job script template {} not found", script.display()));
+            }
+        }
+    }
+
+    fn check_incar(&self, incar: &Incar, sites: Option<&crate::poscar::MagneticSites>, report: &mut crate::check::Report) {
+        for (tag, values) in incar.duplicate_tags() {
+            if values.iter().all(|v| v.eq_ignore_ascii_case(&values[0])) {
+                report.warning("INCAR", format!("{} is set {} times", tag, values.len()));
+            } else {
+                report.error("INCAR", format!("{} is set to conflicting values: {:?}", tag, values));
+            }
+        }
+
+        // the placeholder should appear exactly once, in MAGMOM
+        let placeholder = self.placeholder_text.to_uppercase();
+        let n = incar.tags().filter(|(_, v)| v.to_uppercase().contains(&placeholder)).count();
+        let magmom = incar.get("MAGMOM");
+        match magmom {
+            Some(v) if n == 1 && v.to_uppercase().contains(&placeholder) => {
+                // values for nonmagnetic atoms following the placeholder
+                if let Some(sites) = sites {
+                    let nothers: usize = v.split_whitespace().filter(|x| !x.eq_ignore_ascii_case(&placeholder)).map(count_magmom_values).sum();
+                    let ncomponents = if self.noncollinear_axis().is_some() { 3 } else { 1 };
+                    let nexpected = (sites.natoms - sites.sites.len()) * ncomponents;
+                    if nothers != nexpected {
+                        report.warning(
+                            "INCAR",
+                            format!(
+                                "MAGMOM has {} values for {} nonmagnetic atoms, which is rewritten for all atoms",
+                                nothers,
+                                sites.natoms - sites.sites.len()
+                            ),
+                        );
+                    }
+                }
+            }
+            _ if n > 1 => report.error("INCAR", format!("placeholder {} appears {} times", self.placeholder_text, n)),
+            Some(_) => report.error("INCAR", format!("placeholder {} not found in MAGMOM", self.placeholder_text)),
+            None => report.error("INCAR", format!("placeholder {} not found", self.placeholder_text)),
+        }
+
+        if self.noncollinear_axis().is_none() && incar.get("ISPIN") != Some("2") {
+            report.warning("INCAR", "ISPIN is not 2, which is set to 2 for spin-polarized calculation");
+        }
+        if incar.get("LORBIT").is_none() {
+            report.error("INCAR", "LORBIT is not set, and magnetic moments will not be written into OUTCAR");
+        }
+    }
+}
+
+/// Check species order in POTCAR file `path` against `poscar`.
+fn check_potcar(poscar: &crate::poscar::Poscar, path: &Path, report: &mut crate::check::Report) {
+    let s = match gut::fs::read_file(path) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    // such as "TITEL  = PAW_PBE Fe_pv 02Aug2007"
+    let potcar_species: Vec<_> = s
+        .lines()
+        .filter(|line| line.trim_start().starts_with("TITEL"))
+        .filter_map(|line| line.split('=').nth(1)?.split_whitespace().nth(1)?.split('_').next())
+        .collect();
+    let mut poscar_species = poscar.symbols.clone();
+    poscar_species.dedup();
+    if potcar_species != poscar_species {
+        report.error("POTCAR", format!("species {:?} differ from {:?} in POSCAR", potcar_species, poscar_species));
+    }
+}
+
+/// Return the number of values in MAGMOM item `x`, such as `18*0.0`.
+fn count_magmom_values(x: &str) -> usize {
+    match x.split_once('*') {
+        Some((n, _)) => n.parse().unwrap_or(1),
+        None => 1,
+    }
+}
+// a2f6c93d ends here
+
 /// Get energy from vasp OSZICAR file.
 fn get_energy_from_oszicar<P: AsRef<Path>>(path: P) -> Result<f64> {
     use std::fs::File;
@@ -293,6 +700,62 @@ fn get_energy_from_oszicar<P: AsRef<Path>>(path: P) -> Result<f64> {
 
     bail!("Failed to read energy from {}", oszicar.display());
 }
+
+/// Format `n` zeros in MAGMOM.
+fn format_zeros(n: usize) -> String {
+    if n == 1 {
+        format!("{:4.1}", 0.0)
+    } else {
+        format!("{}*0.0", n)
+    }
+}
+
+/// Get magnetic moment of each ion from the last `magnetization (x)` table
+/// in vasp OUTCAR file.
+fn get_magnetic_moments_from_outcar<P: AsRef<Path>>(path: P) -> Result<Vec<f64>> {
+    get_magnetization_from_outcar(path.as_ref(), "x")
+}
+
+/// Get magnetic moment vector of each ion from the last `magnetization
+/// (x)`, `(y)` and `(z)` tables in OUTCAR file of non-collinear calculation.
+fn get_magnetic_moment_vectors_from_outcar<P: AsRef<Path>>(path: P) -> Result<Vec<[f64; 3]>> {
+    let outcar = path.as_ref();
+    let mx = get_magnetization_from_outcar(outcar, "x")?;
+    let my = get_magnetization_from_outcar(outcar, "y")?;
+    let mz = get_magnetization_from_outcar(outcar, "z")?;
+    if mx.len() != my.len() || mx.len() != mz.len() {
+        bail!("inconsistent magnetization tables in {}", outcar.display());
+    }
+
+    Ok((0..mx.len()).map(|i| [mx[i], my[i], mz[i]]).collect())
+}
+
+/// Get total magnetic moment of each ion from the last `magnetization
+/// (axis)` table in vasp OUTCAR file.
+fn get_magnetization_from_outcar(outcar: &Path, axis: &str) -> Result<Vec<f64>> {
+    let s = gut::fs::read_file(outcar)?;
+    let p = match s.rfind(&format!(" magnetization ({axis})")) {
+        Some(p) => p,
+        None => bail!("No magnetization ({axis}) found in {}. LORBIT missing?", outcar.display()),
+    };
+
+    let mut moments = vec![];
+    // skip header lines
+    for line in s[p..].lines().skip_while(|line| !line.starts_with("---")).skip(1) {
+        if line.starts_with("---") || line.trim().is_empty() {
+            break;
+        }
+        // the total moment in the last column
+        let tot = line.split_whitespace().last().unwrap_or_default();
+        let m: f64 = tot.parse().with_context(|| format!("invalid magnetization line: {line:?}"))?;
+        moments.push(m);
+    }
+    if moments.is_empty() {
+        bail!("Failed to read magnetization from {}", outcar.display());
+    }
+
+    Ok(moments)
+}
 // f6ae3a4b ends here
 
 // [[file:../magman.note::*test][test:1]]
@@ -306,6 +769,70 @@ fn test_get_vasp_energy() -> Result<()> {
     let e = get_energy_from_oszicar(&oszicar)?;
     assert_eq!(e, -204.12640);
 
+    let moments = get_magnetic_moments_from_outcar(adir.join("OUTCAR"))?;
+    assert_eq!(moments.len(), 30);
+    assert_eq!(moments[0], 4.122);
+    assert_eq!(moments[29], -0.215);
+
+    // converged into the requested spin-ordering
+    let sites: Vec<_> = (0..12).collect();
+    let magmoms = [vasp.initial_magmom_value; 12];
+    let so = crate::magmom::parse_spin_key("100100001001")?;
+    let mut ms = crate::magmom::MagneticState::new(&so, e);
+    ms.set_magnetic_moments(&moments, &sites, &magmoms, 0.5, &vasp.spin_states)?;
+    assert!(!ms.spin_flipped);
+    assert_eq!(ms.converged_ordering.as_deref(), Some(&so[..]));
+
+    let so = crate::magmom::parse_spin_key("100100001011")?;
+    let mut ms = crate::magmom::MagneticState::new(&so, e);
+    ms.set_magnetic_moments(&moments, &sites, &magmoms, 0.5, &vasp.spin_states)?;
+    assert!(ms.spin_flipped);
+
+    Ok(())
+}
+
+#[test]
+fn test_check_template() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut vasp = Vasp {
+        template_directory: "tests/files/template".into(),
+        cmdline: "tests/files/template/INCAR".into(),
+        magnetic_sites: Some(crate::poscar::SiteSelection::Species(vec!["Fe".into()])),
+        ..Default::default()
+    };
+
+    let mut report = crate::check::Report::default();
+    vasp.check_template(None, &mut report);
+    assert_eq!(report.nerrors(), 1);
+    assert_eq!(report.problems[0].item, "cmdline");
+    assert_eq!(report.problems.len(), 2);
+    assert!(report.problems[1].message.contains("LMAXMIX"));
+
+    // collect all problems
+    let tdir = dir.path();
+    for f in ["POSCAR", "POTCAR"] {
+        std::fs::copy(vasp.template_directory.join(f), tdir.join(f))?;
+    }
+    let incar = "ISPIN = 1\nMAGMOM = XXXXX 10*0.0\nSYSTEM = XXXXX\nENCUT = 400\nENCUT = 500\n";
+    gut::fs::write_to_file(tdir.join("INCAR"), incar)?;
+    vasp.template_directory = tdir.to_owned();
+    let mut report = crate::check::Report::default();
+    vasp.check_template(Some(10), &mut report);
+    let errors: Vec<_> = report.problems.iter().filter(|p| p.severity == crate::check::Severity::Error).collect();
+    assert_eq!(errors.len(), 6, "{}", report);
+    assert_eq!(errors[0].item, "KPOINTS");
+    assert!(errors[2].message.contains("genome_length"));
+    assert!(errors[3].message.contains("ENCUT"));
+    assert!(errors[4].message.contains("appears 2 times"));
+    assert!(errors[5].message.contains("LORBIT"));
+    assert!(report.problems.iter().any(|p| p.message.contains("ISPIN")));
+
+    // a missing placeholder is an error
+    gut::fs::write_to_file(tdir.join("INCAR"), "ISPIN = 2\nMAGMOM = 10*0.0\nLORBIT = 11\n")?;
+    let mut report = crate::check::Report::default();
+    vasp.check_template(Some(10), &mut report);
+    assert!(report.problems.iter().any(|p| p.severity == crate::check::Severity::Error && p.message.contains("placeholder XXXXX not found")));
+
     Ok(())
 }
 
@@ -316,12 +843,60 @@ fn test_vasp_calculate() -> Result<()> {
     // setup temp directory
     let dir = tempfile::tempdir()?;
 
-    let mut vasp = Vasp::default();
-    vasp.working_directory = dir.path().join("jobs");
-    vasp.template_directory = "tests/files/template".into();
+    let mut vasp = Vasp {
+        working_directory: dir.path().join("jobs"),
+        template_directory: "tests/files/template".into(),
+        magnetic_sites: Some(crate::poscar::SiteSelection::Species(vec!["Fe".into()])),
+        ..Default::default()
+    };
 
-    let so = vec![true, true, false, false];
+    let so = vec![1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0];
+    vasp.prepare_vasp_inputs(&so)?;
+    let incar = gut::fs::read_file(vasp.job_directory(&so).join("INCAR"))?;
+    assert!(incar.contains("  MAGMOM = 5.0  5.0 -5.0 -5.0  5.0  5.0 -5.0 -5.0  5.0  5.0 -5.0 -5.0 18*0.0\n"));
+    assert!(incar.contains("  SYSTEM = fe2o3 cell\n"));
+    assert_eq!(Incar::parse(&incar).get("ISPIN"), Some("2"));
+    assert!(vasp.prepare_vasp_inputs(&so[..4]).is_err());
+
+    // non-contiguous magnetic sites
+    let sites = crate::poscar::MagneticSites {
+        natoms: 6,
+        sites: vec![1, 2, 4],
+        symbols: vec!["Fe".into(), "Cr".into(), "Cr".into()],
+    };
+    assert_eq!(vasp.format_as_vasp_tag(&[1, 0, 0], &sites), " 0.0  5.0 -5.0  0.0 -5.0  0.0");
+
+    // per-species and per-site magnitudes
+    vasp.initial_magmom_values.insert("Cr".into(), 3.0);
+    vasp.initial_magmom_values.insert("5".into(), 4.0);
+    assert_eq!(vasp.format_as_vasp_tag(&[1, 0, 0], &sites), " 0.0  5.0 -3.0  0.0 -4.0  0.0");
+    vasp.initial_magmom_values.clear();
+
+    // up, down and zero
+    vasp.spin_states = crate::spin::SpinStates::new(vec![-1.0, 1.0, 0.0])?;
+    let sites = crate::poscar::MagneticSites {
+        natoms: 3,
+        sites: vec![0, 1, 2],
+        symbols: vec!["Fe".into(); 3],
+    };
+    assert_eq!(vasp.format_as_vasp_tag(&[1, 2, 0], &sites), " 5.0  0.0 -5.0");
+
+    // non-collinear with spins along and perpendicular to SAXIS
+    vasp.spin_states = crate::spin::SpinStates::from_vectors(vec![[0.0, 0.0, 1.0], [1.0, 0.0, 0.0]])?;
+    vasp.saxis = Some([1.0, 0.0, 0.0]);
+    let sites = crate::poscar::MagneticSites {
+        natoms: 3,
+        sites: vec![0, 1],
+        symbols: vec!["Fe".into(); 2],
+    };
+    assert_eq!(vasp.format_as_vasp_tag(&[0, 1], &sites), " 0.0  0.0  5.0  5.0  0.0  0.0 3*0.0");
+    let so = vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     vasp.prepare_vasp_inputs(&so)?;
+    let incar = gut::fs::read_file(vasp.job_directory(&so).join("INCAR"))?;
+    assert!(incar.contains("0.0  0.0  5.0  5.0  0.0  0.0"));
+    assert!(incar.contains(" 54*0.0\n"));
+    assert!(incar.contains("SAXIS = 1 0 0"));
+    assert!(incar.contains("LNONCOLLINEAR = .TRUE."));
 
     let x = cmd!("ls", "-Rl", dir.path()).read()?;
     print!("{}", x);